@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE baskets ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    idempotency_key TEXT NOT NULL UNIQUE,
+    topic TEXT NOT NULL,
+    basket_id UUID NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error TEXT,
+    delivered_at TIMESTAMPTZ,
+    abandoned_at TIMESTAMPTZ
+);
+
+-- The relay only ever looks for pending messages which are due
+CREATE INDEX outbox_pending ON outbox (next_attempt_at)
+    WHERE delivered_at IS NULL AND abandoned_at IS NULL;
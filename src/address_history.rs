@@ -0,0 +1,279 @@
+use std::cmp::{self, Ordering};
+
+use chrono::{NaiveDate, Datelike};
+
+use api::{ApproxDate, DatedAddress, PartialDate};
+
+
+// Something noteworthy about an address history. Indices refer to
+// positions in the history as it is stored, which is sorted by `sort`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressFinding {
+    // No address is still being lived at
+    MissingCurrentAddress,
+    // The address has no start date, so only counts towards the
+    // month it ended in
+    UnknownStartDate { index: usize },
+    // The address ends before it starts, so is ignored
+    EndBeforeStart { index: usize },
+    // No address covers the months from `from` to `to` inclusive
+    Gap { from: ApproxDate, to: ApproxDate, months: u32 },
+    // Two addresses cover the same months
+    Overlap { first: usize, second: usize, months: u32 },
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum AddressFindingKind {
+    MissingCurrentAddress,
+    UnknownStartDate,
+    EndBeforeStart,
+    Gap,
+    Overlap,
+}
+
+impl AddressFinding {
+    pub fn kind(&self) -> AddressFindingKind {
+        match *self {
+            AddressFinding::MissingCurrentAddress => AddressFindingKind::MissingCurrentAddress,
+            AddressFinding::UnknownStartDate { .. } => AddressFindingKind::UnknownStartDate,
+            AddressFinding::EndBeforeStart { .. } => AddressFindingKind::EndBeforeStart,
+            AddressFinding::Gap { .. } => AddressFindingKind::Gap,
+            AddressFinding::Overlap { .. } => AddressFindingKind::Overlap,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressHistoryAnalysis {
+    pub findings: Vec<AddressFinding>,
+    // Total number of months covered by any address. Like every count
+    // of months here, both the first and last month are included.
+    pub months_covered: u32,
+    // Number of months going back from the present without any gaps,
+    // including the current month. This is zero if there is no current
+    // address.
+    pub continuous_months: u32,
+}
+
+impl AddressHistoryAnalysis {
+    pub fn has_current_address(&self) -> bool {
+        !self.findings.contains(&AddressFinding::MissingCurrentAddress)
+    }
+}
+
+
+// Count months from a fixed point, so that periods can be compared
+fn month_index(year: i32, month: u32) -> i32 {
+    year * 12 + month as i32 - 1
+}
+
+fn month_from_index(index: i32) -> ApproxDate {
+    let date = PartialDate::new(index / 12, Some((index % 12) as u32 + 1), None)
+        .expect("Month index out of range");
+    ApproxDate(date)
+}
+
+// The first month an address could have been lived at
+fn start_month(address: &DatedAddress) -> Option<i32> {
+    address.start_date.map(|ApproxDate(date)| month_index(date.year(), date.month().unwrap_or(1)))
+}
+
+// The last month an address could have been lived at, or `None`
+// for a current address.
+fn end_month(address: &DatedAddress) -> Option<i32> {
+    address.end_date.map(|ApproxDate(date)| month_index(date.year(), date.month().unwrap_or(12)))
+}
+
+// Order addresses from most to least recent: current addresses first,
+// then by end date, then by start date.
+fn compare_recency(a: &DatedAddress, b: &DatedAddress) -> Ordering {
+    let end_a = end_month(a).unwrap_or(i32::max_value());
+    let end_b = end_month(b).unwrap_or(i32::max_value());
+    end_b.cmp(&end_a).then_with(|| b.start_date.cmp(&a.start_date))
+}
+
+// Sort an address history so that the most recent address comes first
+pub fn sort(history: &mut [DatedAddress]) {
+    history.sort_by(compare_recency);
+}
+
+pub fn analyse(history: &[DatedAddress], today: NaiveDate) -> AddressHistoryAnalysis {
+    let current_month = month_index(today.year(), today.month());
+    let mut findings = Vec::new();
+
+    // Convert each address into an inclusive range of months
+    let mut periods = Vec::new();
+    let mut has_current_address = false;
+    for (index, address) in history.iter().enumerate() {
+        let end = end_month(address).unwrap_or(current_month);
+        let start = match start_month(address) {
+            Some(start) => start,
+            None => {
+                findings.push(AddressFinding::UnknownStartDate { index });
+                end
+            }
+        };
+        if end < start {
+            // Includes a current address which starts in the future
+            findings.push(AddressFinding::EndBeforeStart { index });
+        } else {
+            has_current_address |= address.end_date.is_none();
+            periods.push((start, end, index));
+        }
+    }
+    if !has_current_address {
+        findings.insert(0, AddressFinding::MissingCurrentAddress);
+    }
+
+    // Walk forwards through time looking for gaps and overlaps,
+    // keeping track of the period which extends furthest.
+    periods.sort();
+    let mut months_covered = 0;
+    let mut latest: Option<(i32, usize)> = None;
+    for &(start, end, index) in &periods {
+        match latest {
+            Some((latest_end, latest_index)) => {
+                if start > latest_end + 1 {
+                    findings.push(AddressFinding::Gap {
+                        from: month_from_index(latest_end + 1),
+                        to: month_from_index(start - 1),
+                        months: (start - latest_end - 1) as u32
+                    });
+                } else if start < latest_end {
+                    // Moving in the same month as moving out is expected,
+                    // so a single shared month is not an overlap.
+                    findings.push(AddressFinding::Overlap {
+                        first: latest_index,
+                        second: index,
+                        months: (cmp::min(end, latest_end) - start + 1) as u32
+                    });
+                }
+                if end > latest_end {
+                    months_covered += (end - cmp::max(start, latest_end + 1) + 1) as u32;
+                    latest = Some((end, index));
+                }
+            },
+            None => {
+                months_covered += (end - start + 1) as u32;
+                latest = Some((end, index));
+            }
+        }
+    }
+
+    // Walk backwards from the present, stopping at the first gap
+    let mut continuous_months = 0;
+    if has_current_address {
+        let mut covered_from = current_month;
+        let mut by_end: Vec<_> = periods.iter().map(|&(start, end, _)| (end, start)).collect();
+        by_end.sort_by(|a, b| b.cmp(a));
+        for (end, start) in by_end {
+            if end < covered_from - 1 {
+                break;
+            }
+            covered_from = cmp::min(covered_from, start);
+        }
+        continuous_months = (current_month - covered_from + 1) as u32;
+    }
+
+    AddressHistoryAnalysis {
+        findings,
+        months_covered,
+        continuous_months,
+    }
+}
+
+
+// Fixtures are shared with the satisfaction tests
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use api::{Address, FreeformAddress};
+
+    pub fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2017, 10, 18)
+    }
+
+    pub fn address(start_date: Option<&str>, end_date: Option<&str>) -> DatedAddress {
+        DatedAddress {
+            start_date: start_date.map(|date| date.parse().unwrap()),
+            end_date: end_date.map(|date| date.parse().unwrap()),
+            address: Address::FreeformAddress(FreeformAddress {
+                country: "GBR".into(),
+                text: "12 St James's Square, London SW1Y 4JH".into()
+            })
+        }
+    }
+
+    #[test]
+    fn sort_most_recent_first() {
+        let mut history = vec![
+            address(Some("2001"), Some("2005-06")),
+            address(Some("2010-01"), None),
+            address(Some("2005-06"), Some("2009-12")),
+        ];
+        sort(&mut history);
+        let starts: Vec<_> = history.iter().map(|a| a.start_date.unwrap().to_string()).collect();
+        assert_eq!(starts, vec!["2010-01", "2005-06", "2001"]);
+    }
+
+    #[test]
+    fn contiguous_history() {
+        let analysis = analyse(&[
+            address(Some("2016-10"), None),
+            address(Some("2010-01"), Some("2016-10")),
+        ], today());
+        assert_eq!(analysis.findings, vec![]);
+        // January 2010 to October 2017, counting both ends
+        assert_eq!(analysis.months_covered, 94);
+        assert_eq!(analysis.continuous_months, 94);
+    }
+
+    #[test]
+    fn gaps_and_overlaps() {
+        let analysis = analyse(&[
+            address(Some("2016-10"), None),
+            address(Some("2014-01"), Some("2016-06")),
+            address(Some("2013-01"), Some("2014-03")),
+        ], today());
+        assert_eq!(analysis.findings, vec![
+            AddressFinding::Overlap { first: 2, second: 1, months: 3 },
+            AddressFinding::Gap {
+                from: "2016-07".parse().unwrap(),
+                to: "2016-09".parse().unwrap(),
+                months: 3
+            },
+        ]);
+        assert_eq!(analysis.continuous_months, 13);
+        assert_eq!(analysis.months_covered, 55);
+    }
+
+    #[test]
+    fn missing_current_address() {
+        let analysis = analyse(&[
+            address(Some("2010-01"), Some("2016-09")),
+            address(None, Some("2009-12")),
+            address(Some("2009"), Some("2008")),
+        ], today());
+        assert_eq!(analysis.findings, vec![
+            AddressFinding::MissingCurrentAddress,
+            AddressFinding::UnknownStartDate { index: 1 },
+            AddressFinding::EndBeforeStart { index: 2 },
+        ]);
+        assert_eq!(analysis.continuous_months, 0);
+        assert!(!analysis.has_current_address());
+    }
+
+    #[test]
+    fn current_address_starting_in_future() {
+        let analysis = analyse(&[
+            address(Some("2018-01"), None),
+            address(Some("2010-01"), Some("2017-10")),
+        ], today());
+        assert_eq!(analysis.findings, vec![
+            AddressFinding::MissingCurrentAddress,
+            AddressFinding::EndBeforeStart { index: 0 },
+        ]);
+        assert_eq!(analysis.continuous_months, 0);
+    }
+}
@@ -1,6 +1,14 @@
 use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use chrono::NaiveDate;
+use regex::{Regex, Captures};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
 use uuid::Uuid;
 
+use error::ApiError;
+
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
@@ -43,6 +51,128 @@ pub enum CheckType {
     CompanyPepsAndSanctionsScreen,
 }
 
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EntityType {
+    Individual,
+    Company,
+}
+
+// Profiles created before entity types were recorded were
+// always individuals.
+impl Default for EntityType {
+    fn default() -> Self {
+        EntityType::Individual
+    }
+}
+
+
+struct TaskInfo {
+    task: TaskType,
+    entity_type: EntityType,
+    checks: &'static [CheckType],
+}
+
+// Which kind of entity each task applies to, and which checks
+// can be used to complete it.
+const TASKS: &'static [TaskInfo] = &[
+    TaskInfo {
+        task: TaskType::IndividualVerifyIdentity,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::IdentityCheck, CheckType::DocumentVerification],
+    },
+    TaskInfo {
+        task: TaskType::IndividualVerifyAddress,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::IdentityCheck, CheckType::DocumentFetch],
+    },
+    TaskInfo {
+        task: TaskType::IndividualVerifySourceOfFunds,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::DocumentFetch],
+    },
+    TaskInfo {
+        task: TaskType::IndividualAssessPoliticalExposure,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::PepsScreen, CheckType::PepsAndSanctionsScreen],
+    },
+    TaskInfo {
+        task: TaskType::IndividualAssessSanctionsExposure,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::SanctionsScreen, CheckType::PepsAndSanctionsScreen],
+    },
+    TaskInfo {
+        task: TaskType::IndividualAssessMediaExposure,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::AdverseMediaScreen],
+    },
+    TaskInfo {
+        task: TaskType::IndividualAssessRegulatoryStatus,
+        entity_type: EntityType::Individual,
+        checks: &[CheckType::DocumentFetch],
+    },
+    TaskInfo {
+        task: TaskType::CompanyVerifyIdentity,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyRegistry],
+    },
+    TaskInfo {
+        task: TaskType::CompanyIdentifyAuthorizedPersons,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyRegistry],
+    },
+    TaskInfo {
+        task: TaskType::CompanyIdentifyOfficers,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyRegistry],
+    },
+    TaskInfo {
+        task: TaskType::CompanyIdentifyBeneficialOwners,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyOwnership],
+    },
+    TaskInfo {
+        task: TaskType::CompanyReviewFilings,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyFilings, CheckType::CompanyFilingPurchase],
+    },
+    TaskInfo {
+        task: TaskType::CompanyAssessPoliticalExposure,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyPepsAndSanctionsScreen],
+    },
+    TaskInfo {
+        task: TaskType::CompanyAssessSanctionsExposure,
+        entity_type: EntityType::Company,
+        checks: &[CheckType::CompanyPepsAndSanctionsScreen],
+    },
+];
+
+impl TaskType {
+    fn info(&self) -> &'static TaskInfo {
+        TASKS.iter()
+            .find(|info| info.task == *self)
+            .expect("Task missing from compatibility table")
+    }
+
+    // All the tasks which apply to the given kind of entity
+    pub fn all_for(entity_type: EntityType) -> Vec<TaskType> {
+        TASKS.iter()
+            .filter(|info| info.entity_type == entity_type)
+            .map(|info| info.task)
+            .collect()
+    }
+
+    pub fn entity_type(&self) -> EntityType {
+        self.info().entity_type
+    }
+
+    // The checks which can be used to complete this task
+    pub fn available_checks(&self) -> &'static [CheckType] {
+        self.info().checks
+    }
+}
+
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone, Ord, PartialOrd)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
@@ -109,10 +239,39 @@ pub enum CollectionStep {
         id: Uuid,
         category: DocumentCategory,
         allowed_types: BTreeSet<DocumentType>
-    }
+    },
+    CompanyName {},
+    RegistrationNumber {},
+    Jurisdiction {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CollectionStepKind {
+    FullName,
+    Dob,
+    AddressHistory,
+    Nationality,
+    Document,
+    CompanyName,
+    RegistrationNumber,
+    Jurisdiction
 }
 
 impl CollectionStep {
+    pub fn kind(&self) -> CollectionStepKind {
+        match *self {
+            CollectionStep::FullName {} => CollectionStepKind::FullName,
+            CollectionStep::Dob {..} => CollectionStepKind::Dob,
+            CollectionStep::AddressHistory {..} => CollectionStepKind::AddressHistory,
+            CollectionStep::Nationality {} => CollectionStepKind::Nationality,
+            CollectionStep::Document {..} => CollectionStepKind::Document,
+            CollectionStep::CompanyName {} => CollectionStepKind::CompanyName,
+            CollectionStep::RegistrationNumber {} => CollectionStepKind::RegistrationNumber,
+            CollectionStep::Jurisdiction {} => CollectionStepKind::Jurisdiction,
+        }
+    }
+
     pub fn try_merge(&mut self, other: &CollectionStep) -> bool {
         use self::CollectionStep::*;
         match (self, other) {
@@ -130,6 +289,9 @@ impl CollectionStep {
                 true
             },
             (&mut Nationality {}, &Nationality {}) => true,
+            (&mut CompanyName {}, &CompanyName {}) => true,
+            (&mut RegistrationNumber {}, &RegistrationNumber {}) => true,
+            (&mut Jurisdiction {}, &Jurisdiction {}) => true,
             (&mut Document {id, ref mut allowed_types, ..}, &Document {id: other_id, allowed_types: ref other_allowed_types, ..}) if id == other_id => {
                 *allowed_types = &*allowed_types & other_allowed_types;
                 true
@@ -157,15 +319,160 @@ pub struct FullName {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(default)]
 pub struct PersonalDetails {
-    name: FullName,
+    pub name: FullName,
     #[serde(skip_serializing_if = "Option::is_none")]
-    dob: Option<PartialDate>,
+    pub dob: Option<PartialDate>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    nationality: Option<String>
+    pub nationality: Option<String>
 }
 
-pub type ApproxDate = String;
-pub type PartialDate = String;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDateError(String);
+
+impl fmt::Display for ParseDateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid date `{}`, expected YYYY, YYYY-MM or YYYY-MM-DD", self.0)
+    }
+}
+
+impl Error for ParseDateError {
+    fn description(&self) -> &str {
+        "invalid date"
+    }
+}
+
+// Serialize a type using its string representation
+macro_rules! string_serde {
+    ($t:ty) => (
+        impl Serialize for $t {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where S: Serializer
+            {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                where D: Deserializer<'de>
+            {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(de::Error::custom)
+            }
+        }
+    )
+}
+
+
+// A date which may only be known to the nearest year or month. Less
+// precise dates sort before more precise dates in the same period, so
+// "2017" < "2017-01" < "2017-01-01".
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PartialDate {
+    year: i32,
+    month: Option<u32>,
+    day: Option<u32>
+}
+
+impl PartialDate {
+    pub fn new(year: i32, month: Option<u32>, day: Option<u32>) -> Option<PartialDate> {
+        let valid = match (month, day) {
+            (None, None) => true,
+            (Some(month), None) => month >= 1 && month <= 12,
+            (Some(month), Some(day)) => NaiveDate::from_ymd_opt(year, month, day).is_some(),
+            (None, Some(_)) => false
+        };
+        if valid && year >= 0 && year <= 9999 {
+            Some(PartialDate { year, month, day })
+        } else {
+            None
+        }
+    }
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+    pub fn month(&self) -> Option<u32> {
+        self.month
+    }
+    pub fn day(&self) -> Option<u32> {
+        self.day
+    }
+    pub fn precision(&self) -> DatePrecision {
+        match (self.month, self.day) {
+            (None, _) => DatePrecision::Year,
+            (Some(_), None) => DatePrecision::YearMonth,
+            (Some(_), Some(_)) => DatePrecision::YearMonthDay
+        }
+    }
+}
+
+impl FromStr for PartialDate {
+    type Err = ParseDateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseDateError(s.into());
+        let parts: Vec<_> = s.split('-').collect();
+        let expected_lengths = [4, 2, 2];
+        if parts.len() > expected_lengths.len() {
+            return Err(err());
+        }
+
+        let mut numbers = Vec::new();
+        for (part, &len) in parts.iter().zip(&expected_lengths) {
+            if part.len() != len || !part.chars().all(|c| c.is_digit(10)) {
+                return Err(err());
+            }
+            numbers.push(part.parse::<u32>().map_err(|_| err())?);
+        }
+
+        PartialDate::new(numbers[0] as i32, numbers.get(1).cloned(), numbers.get(2).cloned())
+            .ok_or_else(err)
+    }
+}
+
+impl fmt::Display for PartialDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}", self.year)?;
+        if let Some(month) = self.month {
+            write!(f, "-{:02}", month)?;
+        }
+        if let Some(day) = self.day {
+            write!(f, "-{:02}", day)?;
+        }
+        Ok(())
+    }
+}
+
+string_serde!(PartialDate);
+
+
+// An approximate date, such as when someone moved into an address.
+// Uses the same formats as `PartialDate`.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ApproxDate(pub PartialDate);
+
+impl ApproxDate {
+    pub fn precision(&self) -> DatePrecision {
+        self.0.precision()
+    }
+}
+
+impl FromStr for ApproxDate {
+    type Err = ParseDateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(ApproxDate)
+    }
+}
+
+impl fmt::Display for ApproxDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+string_serde!(ApproxDate);
 
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -195,8 +502,8 @@ pub struct StructuredAddress {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(default)]
 pub struct FreeformAddress {
-    country: String,
-    text: String
+    pub country: String,
+    pub text: String
 }
 
 
@@ -208,6 +515,216 @@ pub enum Address {
 }
 
 
+// ISO 3166-1 country codes, as (alpha-2, alpha-3) pairs
+const COUNTRIES: &'static [(&'static str, &'static str)] = &[
+    ("AD", "AND"), ("AE", "ARE"), ("AF", "AFG"), ("AG", "ATG"), ("AI", "AIA"), ("AL", "ALB"),
+    ("AM", "ARM"), ("AO", "AGO"), ("AQ", "ATA"), ("AR", "ARG"), ("AS", "ASM"), ("AT", "AUT"),
+    ("AU", "AUS"), ("AW", "ABW"), ("AX", "ALA"), ("AZ", "AZE"), ("BA", "BIH"), ("BB", "BRB"),
+    ("BD", "BGD"), ("BE", "BEL"), ("BF", "BFA"), ("BG", "BGR"), ("BH", "BHR"), ("BI", "BDI"),
+    ("BJ", "BEN"), ("BL", "BLM"), ("BM", "BMU"), ("BN", "BRN"), ("BO", "BOL"), ("BQ", "BES"),
+    ("BR", "BRA"), ("BS", "BHS"), ("BT", "BTN"), ("BV", "BVT"), ("BW", "BWA"), ("BY", "BLR"),
+    ("BZ", "BLZ"), ("CA", "CAN"), ("CC", "CCK"), ("CD", "COD"), ("CF", "CAF"), ("CG", "COG"),
+    ("CH", "CHE"), ("CI", "CIV"), ("CK", "COK"), ("CL", "CHL"), ("CM", "CMR"), ("CN", "CHN"),
+    ("CO", "COL"), ("CR", "CRI"), ("CU", "CUB"), ("CV", "CPV"), ("CW", "CUW"), ("CX", "CXR"),
+    ("CY", "CYP"), ("CZ", "CZE"), ("DE", "DEU"), ("DJ", "DJI"), ("DK", "DNK"), ("DM", "DMA"),
+    ("DO", "DOM"), ("DZ", "DZA"), ("EC", "ECU"), ("EE", "EST"), ("EG", "EGY"), ("EH", "ESH"),
+    ("ER", "ERI"), ("ES", "ESP"), ("ET", "ETH"), ("FI", "FIN"), ("FJ", "FJI"), ("FK", "FLK"),
+    ("FM", "FSM"), ("FO", "FRO"), ("FR", "FRA"), ("GA", "GAB"), ("GB", "GBR"), ("GD", "GRD"),
+    ("GE", "GEO"), ("GF", "GUF"), ("GG", "GGY"), ("GH", "GHA"), ("GI", "GIB"), ("GL", "GRL"),
+    ("GM", "GMB"), ("GN", "GIN"), ("GP", "GLP"), ("GQ", "GNQ"), ("GR", "GRC"), ("GS", "SGS"),
+    ("GT", "GTM"), ("GU", "GUM"), ("GW", "GNB"), ("GY", "GUY"), ("HK", "HKG"), ("HM", "HMD"),
+    ("HN", "HND"), ("HR", "HRV"), ("HT", "HTI"), ("HU", "HUN"), ("ID", "IDN"), ("IE", "IRL"),
+    ("IL", "ISR"), ("IM", "IMN"), ("IN", "IND"), ("IO", "IOT"), ("IQ", "IRQ"), ("IR", "IRN"),
+    ("IS", "ISL"), ("IT", "ITA"), ("JE", "JEY"), ("JM", "JAM"), ("JO", "JOR"), ("JP", "JPN"),
+    ("KE", "KEN"), ("KG", "KGZ"), ("KH", "KHM"), ("KI", "KIR"), ("KM", "COM"), ("KN", "KNA"),
+    ("KP", "PRK"), ("KR", "KOR"), ("KW", "KWT"), ("KY", "CYM"), ("KZ", "KAZ"), ("LA", "LAO"),
+    ("LB", "LBN"), ("LC", "LCA"), ("LI", "LIE"), ("LK", "LKA"), ("LR", "LBR"), ("LS", "LSO"),
+    ("LT", "LTU"), ("LU", "LUX"), ("LV", "LVA"), ("LY", "LBY"), ("MA", "MAR"), ("MC", "MCO"),
+    ("MD", "MDA"), ("ME", "MNE"), ("MF", "MAF"), ("MG", "MDG"), ("MH", "MHL"), ("MK", "MKD"),
+    ("ML", "MLI"), ("MM", "MMR"), ("MN", "MNG"), ("MO", "MAC"), ("MP", "MNP"), ("MQ", "MTQ"),
+    ("MR", "MRT"), ("MS", "MSR"), ("MT", "MLT"), ("MU", "MUS"), ("MV", "MDV"), ("MW", "MWI"),
+    ("MX", "MEX"), ("MY", "MYS"), ("MZ", "MOZ"), ("NA", "NAM"), ("NC", "NCL"), ("NE", "NER"),
+    ("NF", "NFK"), ("NG", "NGA"), ("NI", "NIC"), ("NL", "NLD"), ("NO", "NOR"), ("NP", "NPL"),
+    ("NR", "NRU"), ("NU", "NIU"), ("NZ", "NZL"), ("OM", "OMN"), ("PA", "PAN"), ("PE", "PER"),
+    ("PF", "PYF"), ("PG", "PNG"), ("PH", "PHL"), ("PK", "PAK"), ("PL", "POL"), ("PM", "SPM"),
+    ("PN", "PCN"), ("PR", "PRI"), ("PS", "PSE"), ("PT", "PRT"), ("PW", "PLW"), ("PY", "PRY"),
+    ("QA", "QAT"), ("RE", "REU"), ("RO", "ROU"), ("RS", "SRB"), ("RU", "RUS"), ("RW", "RWA"),
+    ("SA", "SAU"), ("SB", "SLB"), ("SC", "SYC"), ("SD", "SDN"), ("SE", "SWE"), ("SG", "SGP"),
+    ("SH", "SHN"), ("SI", "SVN"), ("SJ", "SJM"), ("SK", "SVK"), ("SL", "SLE"), ("SM", "SMR"),
+    ("SN", "SEN"), ("SO", "SOM"), ("SR", "SUR"), ("SS", "SSD"), ("ST", "STP"), ("SV", "SLV"),
+    ("SX", "SXM"), ("SY", "SYR"), ("SZ", "SWZ"), ("TC", "TCA"), ("TD", "TCD"), ("TF", "ATF"),
+    ("TG", "TGO"), ("TH", "THA"), ("TJ", "TJK"), ("TK", "TKL"), ("TL", "TLS"), ("TM", "TKM"),
+    ("TN", "TUN"), ("TO", "TON"), ("TR", "TUR"), ("TT", "TTO"), ("TV", "TUV"), ("TW", "TWN"),
+    ("TZ", "TZA"), ("UA", "UKR"), ("UG", "UGA"), ("UM", "UMI"), ("US", "USA"), ("UY", "URY"),
+    ("UZ", "UZB"), ("VA", "VAT"), ("VC", "VCT"), ("VE", "VEN"), ("VG", "VGB"), ("VI", "VIR"),
+    ("VN", "VNM"), ("VU", "VUT"), ("WF", "WLF"), ("WS", "WSM"), ("YE", "YEM"), ("YT", "MYT"),
+    ("ZA", "ZAF"), ("ZM", "ZMB"), ("ZW", "ZWE"),
+];
+
+// Find the alpha-3 code for a country given either its alpha-2 or
+// alpha-3 code, ignoring case.
+pub fn normalize_country(code: &str) -> Option<&'static str> {
+    let code = code.trim().to_uppercase();
+    COUNTRIES.iter()
+        .find(|&&(alpha2, alpha3)| code == alpha2 || code == alpha3)
+        .map(|&(_, alpha3)| alpha3)
+}
+
+
+// Postal code formats for the countries we know how to validate, as
+// (alpha-3 country, pattern, separator). The normalized form of a postal
+// code is the groups captured by the pattern, joined by the separator.
+const POSTAL_CODE_FORMATS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("GBR", r"([A-Z]{1,2}[0-9][A-Z0-9]?|GIR) ?([0-9][A-Z]{2})", " "),
+    ("USA", r"([0-9]{5})(?:[- ]?([0-9]{4}))?", "-"),
+    ("DEU", r"([0-9]{5})", ""),
+    ("FRA", r"([0-9]{2}) ?([0-9]{3})", ""),
+    ("NLD", r"([1-9][0-9]{3}) ?([A-Z]{2})", " "),
+];
+
+struct PostalCodeFormat {
+    country: &'static str,
+    // Matches a whole postal code
+    exact: Regex,
+    // Finds a postal code within some other text
+    search: Regex,
+    separator: &'static str,
+}
+
+impl PostalCodeFormat {
+    fn for_country(country: &str) -> Option<&'static PostalCodeFormat> {
+        POSTAL_CODE_REGEXES.iter().find(|format| format.country == country)
+    }
+
+    fn join(&self, captures: &Captures) -> String {
+        captures.iter().skip(1)
+            .filter_map(|group| group.map(|m| m.as_str().to_uppercase()))
+            .collect::<Vec<_>>()
+            .join(self.separator)
+    }
+}
+
+lazy_static! {
+    static ref POSTAL_CODE_REGEXES: Vec<PostalCodeFormat> = POSTAL_CODE_FORMATS.iter()
+        .map(|&(country, pattern, separator)| PostalCodeFormat {
+            country,
+            exact: Regex::new(&format!("^(?:{})$", pattern)).unwrap(),
+            search: Regex::new(&format!(r"(?i)\b(?:{})\b", pattern)).unwrap(),
+            separator
+        })
+        .collect();
+}
+
+// Normalize a postal code for the given alpha-3 country, or return `None`
+// if it is not valid for that country. Postal codes for countries without
+// a known format are accepted as long as they are not blank.
+pub fn normalize_postal_code(country: &str, code: &str) -> Option<String> {
+    let code = code.trim().to_uppercase();
+    match PostalCodeFormat::for_country(country) {
+        Some(format) => format.exact.captures(&code).map(|captures| format.join(&captures)),
+        None if code.is_empty() => None,
+        None => Some(code)
+    }
+}
+
+fn check_country(country: &mut String) -> Result<(), ApiError> {
+    match normalize_country(country) {
+        Some(code) => {
+            *country = code.into();
+            Ok(())
+        },
+        None => Err(ApiError::invalid_input(format!("`{}` is not an ISO 3166-1 country code", country)).at("country"))
+    }
+}
+
+// Split a line such as "12 High Street" or "Hauptstraße 5" into
+// a street number and a route.
+fn split_street(street: &str) -> (Option<String>, String) {
+    let words: Vec<&str> = street.split_whitespace().collect();
+    let is_number = |word: &str| word.chars().next().map_or(false, |c| c.is_digit(10));
+    if words.len() > 1 {
+        if is_number(words[0]) {
+            return (Some(words[0].into()), words[1..].join(" "));
+        }
+        if is_number(words[words.len() - 1]) {
+            return (Some(words[words.len() - 1].into()), words[..words.len() - 1].join(" "));
+        }
+    }
+    (None, street.into())
+}
+
+impl StructuredAddress {
+    // Check the country and postal code, and convert them to
+    // their canonical forms.
+    pub fn normalize(&mut self) -> Result<(), ApiError> {
+        check_country(&mut self.country)?;
+        match normalize_postal_code(&self.country, &self.postal_code) {
+            Some(code) => self.postal_code = code,
+            None => return Err(ApiError::invalid_input(
+                format!("`{}` is not a valid postal code for {}", self.postal_code, self.country)
+            ).at("postalCode"))
+        }
+        Ok(())
+    }
+}
+
+impl FreeformAddress {
+    pub fn normalize(&mut self) -> Result<(), ApiError> {
+        check_country(&mut self.country)
+    }
+
+    // Make a best guess at the components of the address. The first line
+    // is taken to be the street, and the last line the town. Anything that
+    // cannot be recognised is left blank.
+    pub fn to_structured(&self) -> StructuredAddress {
+        let country = normalize_country(&self.country).map_or_else(|| self.country.clone(), Into::into);
+        let mut text = self.text.clone();
+        let mut postal_code = String::new();
+        if let Some(format) = PostalCodeFormat::for_country(&country) {
+            let found = format.search.captures(&text).map(|captures| {
+                let m = captures.get(0).unwrap();
+                (format.join(&captures), m.start(), m.end())
+            });
+            if let Some((code, start, end)) = found {
+                postal_code = code;
+                text = format!("{}{}", &text[..start], &text[end..]);
+            }
+        }
+
+        let mut lines: Vec<&str> = text.split(|c| c == ',' || c == '\n')
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        let mut address = StructuredAddress {
+            country,
+            postal_code,
+            ..Default::default()
+        };
+        if !lines.is_empty() {
+            let (street_number, route) = split_street(lines.remove(0));
+            address.street_number = street_number;
+            address.route = Some(route);
+        }
+        if let Some(town) = lines.pop() {
+            address.postal_town = Some(town.into());
+        }
+        if !lines.is_empty() {
+            address.locality = Some(lines.join(", "));
+        }
+        address
+    }
+}
+
+impl Address {
+    pub fn normalize(&mut self) -> Result<(), ApiError> {
+        match *self {
+            Address::StructuredAddress(ref mut address) => address.normalize().map_err(|e| e.at("structured")),
+            Address::FreeformAddress(ref mut address) => address.normalize().map_err(|e| e.at("freeform"))
+        }
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DatedAddress {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -230,17 +747,113 @@ pub struct Document {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(default)]
 pub struct IndividualData {
-    personal_details: PersonalDetails,
+    pub personal_details: PersonalDetails,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_history: Option<Vec<DatedAddress>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documents: Option<Vec<Document>>
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OfficerRole {
+    Director,
+    Secretary,
+    Partner,
+    Other
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Officer {
+    pub name: String,
+    pub role: OfficerRole,
     #[serde(skip_serializing_if = "Option::is_none")]
-    address_history: Option<Vec<DatedAddress>>,
+    pub appointed_on: Option<PartialDate>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    documents: Option<Vec<Document>>
+    pub resigned_on: Option<PartialDate>
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BeneficialOwner {
+    pub name: String,
+    pub entity_type: EntityType,
+    // Percentage of the company owned, from 0 to 100
+    pub ownership_percentage: f64
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Filing {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    pub document_type: DocumentType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filed_on: Option<PartialDate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>
 }
 
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(default)]
 pub struct CompanyData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legal_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registration_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jurisdiction: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registered_address: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incorporation_date: Option<PartialDate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub officers: Option<Vec<Officer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beneficial_owners: Option<Vec<BeneficialOwner>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filings: Option<Vec<Filing>>
+}
+
+impl CompanyData {
+    // Check the company data is self-consistent, and convert addresses
+    // and jurisdictions to their canonical forms.
+    pub fn normalize(&mut self) -> Result<(), ApiError> {
+        if let Some(ref mut jurisdiction) = self.jurisdiction {
+            check_country(jurisdiction).map_err(|_| ApiError::invalid_input(
+                format!("`{}` is not an ISO 3166-1 country code", jurisdiction)
+            ).at("jurisdiction"))?;
+        }
+        if let Some(ref mut address) = self.registered_address {
+            address.normalize().map_err(|e| e.at("registeredAddress"))?;
+        }
+        for officer in self.officers.iter().flat_map(|officers| officers) {
+            if let (Some(appointed_on), Some(resigned_on)) = (officer.appointed_on, officer.resigned_on) {
+                if resigned_on < appointed_on {
+                    return Err(ApiError::invalid_input(format!("{} resigned before being appointed", officer.name))
+                        .at("officers"));
+                }
+            }
+        }
+        if let Some(ref owners) = self.beneficial_owners {
+            let mut total = 0.0;
+            for (i, owner) in owners.iter().enumerate() {
+                if !(owner.ownership_percentage > 0.0 && owner.ownership_percentage <= 100.0) {
+                    return Err(ApiError::invalid_input("Ownership percentage must be greater than 0 and at most 100")
+                        .at("ownershipPercentage").at(format!("[{}]", i)).at("beneficialOwners"));
+                }
+                total += owner.ownership_percentage;
+            }
+            // Allow for rounding when shares are given as decimals
+            if total > 100.0 + 1e-9 {
+                return Err(ApiError::invalid_input(format!("Beneficial owners hold {}% of the company in total", total))
+                    .at("beneficialOwners"));
+            }
+        }
+        Ok(())
+    }
 }
 
 
@@ -250,3 +863,179 @@ pub enum EntityData {
     IndividualData(IndividualData),
     CompanyData(CompanyData)
 }
+
+impl EntityData {
+    pub fn entity_type(&self) -> EntityType {
+        match *self {
+            EntityData::IndividualData(_) => EntityType::Individual,
+            EntityData::CompanyData(_) => EntityType::Company,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn parse_partial_dates() {
+        let date: PartialDate = "2017".parse().unwrap();
+        assert_eq!(date.precision(), DatePrecision::Year);
+        let date: PartialDate = "2017-02".parse().unwrap();
+        assert_eq!((date.year(), date.month(), date.precision()), (2017, Some(2), DatePrecision::YearMonth));
+        let date: PartialDate = "2016-02-29".parse().unwrap();
+        assert_eq!(date.precision(), DatePrecision::YearMonthDay);
+
+        for invalid in &["banana", "", "17", "2017-2", "2017-13", "2017-02-29", "2017-01-01-01", "+017"] {
+            assert!(invalid.parse::<PartialDate>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn partial_date_round_trip() {
+        for s in &["0999", "2017-01", "2017-01-09"] {
+            let date: PartialDate = s.parse().unwrap();
+            assert_eq!(date.to_string(), *s);
+            let json = serde_json::to_string(&date).unwrap();
+            assert_eq!(json, format!("\"{}\"", s));
+            assert_eq!(serde_json::from_str::<PartialDate>(&json).unwrap(), date);
+        }
+        assert!(serde_json::from_str::<ApproxDate>("\"banana\"").is_err());
+    }
+
+    #[test]
+    fn partial_dates_order_chronologically() {
+        let mut dates: Vec<PartialDate> = ["2017-01-02", "2016-12", "2017", "2017-01-01", "2016-12-31"]
+            .iter().map(|s| s.parse().unwrap()).collect();
+        dates.sort();
+        let sorted: Vec<_> = dates.iter().map(|d| d.to_string()).collect();
+        assert_eq!(sorted, vec!["2016-12", "2016-12-31", "2017", "2017-01-01", "2017-01-02"]);
+    }
+
+    const EVERY_TASK: [TaskType; 14] = [
+        TaskType::IndividualVerifyIdentity,
+        TaskType::IndividualVerifyAddress,
+        TaskType::IndividualVerifySourceOfFunds,
+        TaskType::IndividualAssessPoliticalExposure,
+        TaskType::IndividualAssessSanctionsExposure,
+        TaskType::IndividualAssessMediaExposure,
+        TaskType::IndividualAssessRegulatoryStatus,
+        TaskType::CompanyVerifyIdentity,
+        TaskType::CompanyIdentifyAuthorizedPersons,
+        TaskType::CompanyIdentifyOfficers,
+        TaskType::CompanyIdentifyBeneficialOwners,
+        TaskType::CompanyReviewFilings,
+        TaskType::CompanyAssessPoliticalExposure,
+        TaskType::CompanyAssessSanctionsExposure,
+    ];
+
+    // The match is exhaustive, so a new task fails to compile until it
+    // is added here, as a reminder to add it to `EVERY_TASK` as well.
+    fn expected_entity_type(task: TaskType) -> EntityType {
+        match task {
+            TaskType::IndividualVerifyIdentity |
+            TaskType::IndividualVerifyAddress |
+            TaskType::IndividualVerifySourceOfFunds |
+            TaskType::IndividualAssessPoliticalExposure |
+            TaskType::IndividualAssessSanctionsExposure |
+            TaskType::IndividualAssessMediaExposure |
+            TaskType::IndividualAssessRegulatoryStatus => EntityType::Individual,
+            TaskType::CompanyVerifyIdentity |
+            TaskType::CompanyIdentifyAuthorizedPersons |
+            TaskType::CompanyIdentifyOfficers |
+            TaskType::CompanyIdentifyBeneficialOwners |
+            TaskType::CompanyReviewFilings |
+            TaskType::CompanyAssessPoliticalExposure |
+            TaskType::CompanyAssessSanctionsExposure => EntityType::Company,
+        }
+    }
+
+    #[test]
+    fn every_task_is_in_compatibility_table() {
+        assert_eq!(EVERY_TASK.len(), TASKS.len());
+
+        for task in &EVERY_TASK {
+            // Panics if the task is missing from the table
+            let info = task.info();
+            assert!(!info.checks.is_empty(), "{:?} has no checks", task);
+            assert_eq!(info.entity_type, expected_entity_type(*task));
+            assert!(TaskType::all_for(info.entity_type).contains(task));
+        }
+    }
+
+    #[test]
+    fn checks_apply_to_one_kind_of_entity() {
+        for info in TASKS {
+            for other in TASKS {
+                if info.entity_type != other.entity_type {
+                    for check in info.checks {
+                        assert!(!other.checks.contains(check), "{:?} is used for both kinds of entity", check);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn country_codes() {
+        assert_eq!(normalize_country("gb"), Some("GBR"));
+        assert_eq!(normalize_country("NLD"), Some("NLD"));
+        assert_eq!(normalize_country("UK"), None);
+        assert_eq!(normalize_country(""), None);
+    }
+
+    #[test]
+    fn postal_codes() {
+        assert_eq!(normalize_postal_code("GBR", "sw1y4jh"), Some("SW1Y 4JH".into()));
+        assert_eq!(normalize_postal_code("GBR", "12345"), None);
+        assert_eq!(normalize_postal_code("USA", "20500"), Some("20500".into()));
+        assert_eq!(normalize_postal_code("USA", "20500 0003"), Some("20500-0003".into()));
+        assert_eq!(normalize_postal_code("DEU", "10115"), Some("10115".into()));
+        assert_eq!(normalize_postal_code("DEU", "1011"), None);
+        assert_eq!(normalize_postal_code("FRA", "75 008"), Some("75008".into()));
+        assert_eq!(normalize_postal_code("NLD", "1012jS"), Some("1012 JS".into()));
+        assert_eq!(normalize_postal_code("NLD", "0123 AB"), None);
+        assert_eq!(normalize_postal_code("JPN", " 100-0001 "), Some("100-0001".into()));
+        assert_eq!(normalize_postal_code("JPN", " "), None);
+    }
+
+    #[test]
+    fn normalize_structured_address() {
+        let mut address = StructuredAddress {
+            country: "gb".into(),
+            postal_code: "sw1y 4jh".into(),
+            ..Default::default()
+        };
+        address.normalize().unwrap();
+        assert_eq!((&*address.country, &*address.postal_code), ("GBR", "SW1Y 4JH"));
+
+        address.postal_code = "not a postcode".into();
+        let error = Address::StructuredAddress(address).normalize().unwrap_err();
+        assert_eq!(error.field.as_ref().map(|f| &**f), Some("structured.postalCode"));
+    }
+
+    #[test]
+    fn freeform_to_structured() {
+        let address = FreeformAddress {
+            country: "GB".into(),
+            text: "12 St James's Square, St James's, London SW1Y 4JH".into()
+        }.to_structured();
+        assert_eq!(address.country, "GBR");
+        assert_eq!(address.postal_code, "SW1Y 4JH");
+        assert_eq!(address.street_number, Some("12".into()));
+        assert_eq!(address.route, Some("St James's Square".into()));
+        assert_eq!(address.locality, Some("St James's".into()));
+        assert_eq!(address.postal_town, Some("London".into()));
+
+        let address = FreeformAddress {
+            country: "DEU".into(),
+            text: "Unter den Linden 77\n10117 Berlin".into()
+        }.to_structured();
+        assert_eq!(address.postal_code, "10117");
+        assert_eq!(address.street_number, Some("77".into()));
+        assert_eq!(address.route, Some("Unter den Linden".into()));
+        assert_eq!(address.postal_town, Some("Berlin".into()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use error::ApiError;
+
+
+// Characters allowed in the local part of an email address, other
+// than letters, digits and dots.
+const LOCAL_PART_SYMBOLS: &'static str = "!#$%&'*+/=?^_`{|}~-";
+
+fn is_alphanumeric(c: char) -> bool {
+    match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' => true,
+        _ => false
+    }
+}
+
+fn is_valid_local_part(local: &str) -> bool {
+    !local.is_empty() && local.len() <= 64 &&
+        !local.starts_with('.') && !local.ends_with('.') && !local.contains("..") &&
+        local.chars().all(|c| is_alphanumeric(c) || c == '.' || LOCAL_PART_SYMBOLS.contains(c))
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    !domain.is_empty() && domain.len() <= 253 && labels.len() >= 2 &&
+        labels.iter().all(|label| {
+            !label.is_empty() && label.len() <= 63 &&
+                !label.starts_with('-') && !label.ends_with('-') &&
+                label.chars().all(|c| is_alphanumeric(c) || c == '-')
+        })
+}
+
+// Check an email address against the common subset of RFC 5322: a
+// dot-atom local part and a domain name. Quoted local parts, comments
+// and IP address literals are not accepted. The domain is lower-cased,
+// since it is not case-sensitive.
+pub fn normalize_email(address: &str) -> Result<String, ApiError> {
+    let address = address.trim();
+    let invalid = || ApiError::invalid_input(format!("`{}` is not a valid email address", address));
+    let at = address.rfind('@').ok_or_else(&invalid)?;
+    let (local, domain) = (&address[..at], &address[at + 1..]);
+    if address.len() > 254 || !is_valid_local_part(local) || !is_valid_domain(domain) {
+        return Err(invalid());
+    }
+    Ok(format!("{}@{}", local, domain.to_lowercase()))
+}
+
+
+// Country calling codes assigned by the ITU. No code is a prefix of
+// another, so the calling code of a number is the one it starts with.
+const CALLING_CODES: &'static [&'static str] = &[
+    "1", "7",
+    "20", "27", "30", "31", "32", "33", "34", "36", "39", "40", "41", "43", "44", "45", "46",
+    "47", "48", "49", "51", "52", "53", "54", "55", "56", "57", "58", "60", "61", "62", "63",
+    "64", "65", "66", "81", "82", "84", "86", "90", "91", "92", "93", "94", "95", "98",
+    "211", "212", "213", "216", "218", "220", "221", "222", "223", "224", "225", "226", "227",
+    "228", "229", "230", "231", "232", "233", "234", "235", "236", "237", "238", "239", "240",
+    "241", "242", "243", "244", "245", "246", "247", "248", "249", "250", "251", "252", "253",
+    "254", "255", "256", "257", "258", "260", "261", "262", "263", "264", "265", "266", "267",
+    "268", "269", "290", "291", "297", "298", "299", "350", "351", "352", "353", "354", "355",
+    "356", "357", "358", "359", "370", "371", "372", "373", "374", "375", "376", "377", "378",
+    "379", "380", "381", "382", "383", "385", "386", "387", "389", "420", "421", "423", "500",
+    "501", "502", "503", "504", "505", "506", "507", "508", "509", "590", "591", "592", "593",
+    "594", "595", "596", "597", "598", "599", "670", "672", "673", "674", "675", "676", "677",
+    "678", "679", "680", "681", "682", "683", "685", "686", "687", "688", "689", "690", "691",
+    "692", "800", "808", "850", "852", "853", "855", "856", "870", "878", "880", "881", "882",
+    "883", "886", "888", "960", "961", "962", "963", "964", "965", "966", "967", "968", "970",
+    "971", "972", "973", "974", "975", "976", "977", "979", "992", "993", "994", "995", "996",
+    "998",
+];
+
+// Calling codes for countries which dial a trunk prefix before
+// national numbers.
+const TRUNK_PREFIXES: &'static [(&'static str, &'static str)] = &[
+    ("7", "8"), ("20", "0"), ("27", "0"), ("31", "0"), ("32", "0"), ("33", "0"), ("36", "06"),
+    ("41", "0"), ("43", "0"), ("44", "0"), ("46", "0"), ("49", "0"), ("61", "0"), ("62", "0"),
+    ("63", "0"), ("64", "0"), ("66", "0"), ("81", "0"), ("82", "0"), ("84", "0"), ("86", "0"),
+    ("90", "0"), ("91", "0"), ("92", "0"), ("353", "0"),
+];
+
+fn trunk_prefix(calling_code: &str) -> Option<&'static str> {
+    TRUNK_PREFIXES.iter()
+        .find(|&&(code, _)| code == calling_code)
+        .map(|&(_, prefix)| prefix)
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhoneNumber {
+    pub calling_code: &'static str,
+    pub national_number: String,
+}
+
+impl PhoneNumber {
+    // Parse a phone number in international format, either with a
+    // leading `+` or `00`. Spaces, dots, dashes and brackets are ignored,
+    // as is a trunk prefix after the calling code, eg. `+44 (0)20`.
+    pub fn parse(input: &str) -> Result<PhoneNumber, ApiError> {
+        let invalid = |reason: &str| ApiError::invalid_input(format!("`{}` is not a valid phone number: {}", input, reason));
+
+        let trimmed = input.trim();
+        let rest = if trimmed.starts_with('+') {
+            &trimmed[1..]
+        } else if trimmed.starts_with("00") {
+            &trimmed[2..]
+        } else {
+            return Err(invalid("it must start with `+` and a country calling code"));
+        };
+
+        let mut digits = String::new();
+        // Where each group of digits in brackets starts, and its digits
+        let mut bracketed: Vec<(usize, String)> = Vec::new();
+        let mut in_brackets = false;
+        for c in rest.chars() {
+            match c {
+                '0'...'9' => {
+                    digits.push(c);
+                    if in_brackets {
+                        if let Some(&mut (_, ref mut group)) = bracketed.last_mut() {
+                            group.push(c);
+                        }
+                    }
+                },
+                '(' => {
+                    in_brackets = true;
+                    bracketed.push((digits.len(), String::new()));
+                },
+                ')' => in_brackets = false,
+                ' ' | '.' | '-' => {},
+                _ => return Err(invalid("it contains unexpected characters")),
+            }
+        }
+
+        let calling_code = *CALLING_CODES.iter()
+            .find(|&&code| digits.starts_with(code))
+            .ok_or_else(|| invalid("the country calling code is not recognised"))?;
+        let mut national_number = &digits[calling_code.len()..];
+        // The trunk prefix is only dialled within the country. It can be
+        // recognised when it is in brackets, or when it starts with `0`,
+        // since national numbers in those countries never do.
+        if let Some(prefix) = trunk_prefix(calling_code) {
+            let in_brackets = bracketed.iter()
+                .any(|&(start, ref group)| start == calling_code.len() && group == prefix);
+            if in_brackets || (prefix.starts_with('0') && national_number.starts_with(prefix)) {
+                national_number = &national_number[prefix.len()..];
+            }
+        }
+        if calling_code.len() + national_number.len() > 15 {
+            return Err(invalid("it is too long"));
+        }
+        if national_number.len() < 4 {
+            return Err(invalid("it is too short"));
+        }
+        Ok(PhoneNumber {
+            calling_code,
+            national_number: national_number.into(),
+        })
+    }
+
+    pub fn to_e164(&self) -> String {
+        self.to_string()
+    }
+
+    // The number as it would be dialled from within the country
+    pub fn national_format(&self) -> String {
+        let n = &self.national_number;
+        if self.calling_code == "1" && n.len() == 10 {
+            return format!("({}) {}-{}", &n[..3], &n[3..6], &n[6..]);
+        }
+        format!("{}{}", trunk_prefix(self.calling_code).unwrap_or(""), n)
+    }
+}
+
+// Formats the number as E.164
+impl fmt::Display for PhoneNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "+{}{}", self.calling_code, self.national_number)
+    }
+}
+
+pub fn normalize_phone_number(input: &str) -> Result<String, ApiError> {
+    PhoneNumber::parse(input).map(|number| number.to_e164())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_emails() {
+        assert_eq!(normalize_email(" ada@Example.COM ").unwrap(), "ada@example.com");
+        assert_eq!(normalize_email("Ada.Lovelace+basket@mail.example.co.uk").unwrap(), "Ada.Lovelace+basket@mail.example.co.uk");
+        assert_eq!(normalize_email("o'brien@example.ie").unwrap(), "o'brien@example.ie");
+    }
+
+    #[test]
+    fn invalid_emails() {
+        for address in &["", "ada", "@example.com", "ada@", "ada@localhost", "ada..lovelace@example.com",
+                         ".ada@example.com", "ada@-example.com", "ada@example..com", "ada lovelace@example.com",
+                         "\"ada\"@example.com", "ada@[127.0.0.1]"] {
+            assert!(normalize_email(address).is_err(), "{} should be invalid", address);
+        }
+    }
+
+    #[test]
+    fn parse_phone_numbers() {
+        let number = PhoneNumber::parse("+44 7700 900123").unwrap();
+        assert_eq!(number.calling_code, "44");
+        assert_eq!(number.to_e164(), "+447700900123");
+        assert_eq!(number.national_format(), "07700900123");
+
+        let number = PhoneNumber::parse("001 202.555.0123").unwrap();
+        assert_eq!(number.to_e164(), "+12025550123");
+        assert_eq!(number.national_format(), "(202) 555-0123");
+
+        // The trunk prefix is not part of the international number
+        for input in &["+44 (0)20 7946 0000", "+44 020 7946 0000", "0044 (0) 20-7946-0000"] {
+            let number = PhoneNumber::parse(input).unwrap();
+            assert_eq!(number.to_e164(), "+442079460000");
+            assert_eq!(number.national_format(), "02079460000");
+        }
+        let number = PhoneNumber::parse("+7 (8) 812 123-45-67").unwrap();
+        assert_eq!(number.to_e164(), "+78121234567");
+        let number = PhoneNumber::parse("+7 812 123-45-67").unwrap();
+        assert_eq!(number.to_e164(), "+78121234567");
+
+        let number = PhoneNumber::parse("+352 4796 1").unwrap();
+        assert_eq!(number.calling_code, "352");
+        assert_eq!(number.national_format(), "47961");
+    }
+
+    #[test]
+    fn invalid_phone_numbers() {
+        for number in &["07700 900123", "+", "+44 12", "+999 1234 5678", "+44 7700 900123 ext 4",
+                        "+44 1234 5678 9012 3456"] {
+            assert!(PhoneNumber::parse(number).is_err(), "{} should be invalid", number);
+        }
+    }
+}
@@ -1,21 +1,179 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
 use schema::*;
 use uuid::Uuid;
+use chrono::{self, DateTime, Utc};
+
+use error::ErrorCode;
+use outbox::{Outbox, OutboxMessage};
+use webhooks::{Webhook, WebhookDelivery};
+
+// Everything that can go wrong when talking to a database backend
+#[derive(Debug, Clone)]
+pub enum DatabaseError {
+    // Timed out waiting for a connection from the pool
+    PoolTimeout,
+    // The transaction conflicted with a concurrent transaction,
+    // and retrying did not resolve the conflict
+    SerializationConflict,
+    // The requested row does not exist
+    NotFound,
+    // A stored JSONB value could not be deserialized
+    CorruptData(String),
+    // The migrations could not be applied
+    MigrationFailed(String),
+    // The update callback asked for the transaction to be rolled back
+    RolledBack,
+    // Any other error reported by the database
+    Query(String),
+}
+
+impl DatabaseError {
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            DatabaseError::PoolTimeout => ErrorCode::PoolTimeout,
+            DatabaseError::SerializationConflict => ErrorCode::SerializationConflict,
+            DatabaseError::NotFound => ErrorCode::NotFound,
+            DatabaseError::CorruptData(_) => ErrorCode::CorruptData,
+            DatabaseError::MigrationFailed(_) => ErrorCode::MigrationFailed,
+            DatabaseError::RolledBack |
+            DatabaseError::Query(_) => ErrorCode::DatabaseError,
+        }
+    }
+
+    // Whether running the transaction again might succeed
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            DatabaseError::SerializationConflict => true,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DatabaseError::PoolTimeout => write!(f, "Timed out obtaining a database connection"),
+            DatabaseError::SerializationConflict => write!(f, "Conflicting concurrent update, please retry"),
+            DatabaseError::NotFound => write!(f, "Not found"),
+            DatabaseError::CorruptData(ref msg) => write!(f, "Stored data could not be read: {}", msg),
+            DatabaseError::MigrationFailed(ref msg) => write!(f, "Failed to run migration: {}", msg),
+            DatabaseError::RolledBack => write!(f, "Transaction was rolled back"),
+            DatabaseError::Query(ref msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl Error for DatabaseError {
+    fn description(&self) -> &str {
+        "database error"
+    }
+}
+
+pub type DatabaseResult<T> = Result<T, DatabaseError>;
+
+// Returned by operations which a backend does not provide, so that
+// callers such as the outbox relay report an error instead of panicking.
+fn not_supported<T>() -> DatabaseResult<T> {
+    Err(DatabaseError::Query("not supported".into()))
+}
 
 // Database must:
 // - be thread-safe (Send + Sync)
 // - live as long as required ('static)
 pub trait Database: Send + Sync + 'static + Debug {
-    fn update_basket_impl(&self, _basket_id: Uuid, _f: &mut FnMut(&mut Basket)) -> Basket { unimplemented!() }
-    fn migrate(&self) { unimplemented!() }
+    // Fetch a basket without modifying it, or `None` if it does not exist.
+    fn get_basket(&self, basket_id: Uuid) -> DatabaseResult<Option<Basket>>;
+    // Run `f` against the basket within a transaction. The changes, and
+    // any messages added to the outbox, are only committed if `f` returns true.
+    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut Basket, &mut Outbox) -> bool) -> DatabaseResult<Basket>;
+    fn migrate(&self) -> DatabaseResult<()> { not_supported() }
+
+    // Return up to `limit` outbox messages which are due, and hide them
+    // from other callers until the lease expires.
+    fn claim_outbox_messages(&self, _limit: usize, _lease: chrono::Duration) -> DatabaseResult<Vec<OutboxMessage>> { not_supported() }
+    fn complete_outbox_message(&self, _id: i64) -> DatabaseResult<()> { not_supported() }
+    // Record a failed attempt. The message is abandoned if there
+    // is no `next_attempt_at`.
+    fn retry_outbox_message(&self, _id: i64, _error: &str, _next_attempt_at: Option<DateTime<Utc>>) -> DatabaseResult<()> { not_supported() }
+
+    fn insert_webhook(&self, _webhook: &Webhook) -> DatabaseResult<()> { not_supported() }
+    // Delete a webhook along with its delivery log, returning the
+    // webhook if it existed.
+    fn delete_webhook(&self, _webhook_id: Uuid) -> DatabaseResult<Option<Webhook>> { not_supported() }
+    // Every webhook registered by a tenant, oldest first
+    fn get_webhooks(&self, _tenant_id: &str) -> DatabaseResult<Vec<Webhook>> { not_supported() }
+    fn insert_webhook_delivery(&self, _delivery: &WebhookDelivery) -> DatabaseResult<()> { not_supported() }
+    // The most recent delivery attempts for a webhook, newest first
+    fn get_webhook_deliveries(&self, _webhook_id: Uuid, _limit: usize) -> DatabaseResult<Vec<WebhookDelivery>> { not_supported() }
+    // Whether an event has already been delivered to a webhook
+    fn has_webhook_succeeded(&self, _webhook_id: Uuid, _event_id: &str) -> DatabaseResult<bool> { not_supported() }
+}
+
+// Allows a database to be shared, eg. with the outbox relay
+impl<D: Database + ?Sized> Database for Arc<D> {
+    fn get_basket(&self, basket_id: Uuid) -> DatabaseResult<Option<Basket>> {
+        (**self).get_basket(basket_id)
+    }
+    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut Basket, &mut Outbox) -> bool) -> DatabaseResult<Basket> {
+        (**self).update_basket_impl(basket_id, f)
+    }
+    fn migrate(&self) -> DatabaseResult<()> {
+        (**self).migrate()
+    }
+    fn claim_outbox_messages(&self, limit: usize, lease: chrono::Duration) -> DatabaseResult<Vec<OutboxMessage>> {
+        (**self).claim_outbox_messages(limit, lease)
+    }
+    fn complete_outbox_message(&self, id: i64) -> DatabaseResult<()> {
+        (**self).complete_outbox_message(id)
+    }
+    fn retry_outbox_message(&self, id: i64, error: &str, next_attempt_at: Option<DateTime<Utc>>) -> DatabaseResult<()> {
+        (**self).retry_outbox_message(id, error, next_attempt_at)
+    }
+    fn insert_webhook(&self, webhook: &Webhook) -> DatabaseResult<()> {
+        (**self).insert_webhook(webhook)
+    }
+    fn delete_webhook(&self, webhook_id: Uuid) -> DatabaseResult<Option<Webhook>> {
+        (**self).delete_webhook(webhook_id)
+    }
+    fn get_webhooks(&self, tenant_id: &str) -> DatabaseResult<Vec<Webhook>> {
+        (**self).get_webhooks(tenant_id)
+    }
+    fn insert_webhook_delivery(&self, delivery: &WebhookDelivery) -> DatabaseResult<()> {
+        (**self).insert_webhook_delivery(delivery)
+    }
+    fn get_webhook_deliveries(&self, webhook_id: Uuid, limit: usize) -> DatabaseResult<Vec<WebhookDelivery>> {
+        (**self).get_webhook_deliveries(webhook_id, limit)
+    }
+    fn has_webhook_succeeded(&self, webhook_id: Uuid, event_id: &str) -> DatabaseResult<bool> {
+        (**self).has_webhook_succeeded(webhook_id, event_id)
+    }
 }
 
 impl Database {
-    pub fn update_basket<E, F: FnMut(&mut Basket) -> Result<(), E>>(&self, basket_id: Uuid, f: &mut F) -> Result<Basket, E> {
+    pub fn update_basket<E, F>(&self, basket_id: Uuid, f: &mut F) -> Result<Basket, E>
+        where E: From<DatabaseError>, F: FnMut(&mut Basket) -> Result<(), E>
+    {
+        self.update_basket_with_outbox(basket_id, &mut |basket: &mut Basket, _: &mut Outbox| f(basket))
+    }
+
+    // As `update_basket`, but `f` may also add messages to the outbox
+    pub fn update_basket_with_outbox<E, F>(&self, basket_id: Uuid, f: &mut F) -> Result<Basket, E>
+        where E: From<DatabaseError>, F: FnMut(&mut Basket, &mut Outbox) -> Result<(), E>
+    {
         let mut result = None;
-        let basket = self.update_basket_impl(basket_id, &mut |basket| {
-            result = Some(f(basket));
+        let basket = self.update_basket_impl(basket_id, &mut |basket, outbox| {
+            let r = f(basket, outbox);
+            let ok = r.is_ok();
+            result = Some(r);
+            ok
         });
-        result.expect("Failed to execute callback!").map(|_| basket)
+        // An error from the callback takes precedence over the
+        // rollback it caused.
+        if let Some(Err(e)) = result {
+            return Err(e);
+        }
+        Ok(basket?)
     }
 }
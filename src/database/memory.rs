@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use uuid::Uuid;
+use chrono::{self, DateTime, Utc};
+
+use schema::*;
+use outbox::{Outbox, OutboxMessage};
+use webhooks::{Webhook, WebhookDelivery};
+use database::interface::{Database, DatabaseError, DatabaseResult};
+
+#[derive(Debug, Default)]
+struct State {
+    baskets: HashMap<Uuid, Basket>,
+    outbox: Vec<OutboxMessage>,
+    webhooks: Vec<Webhook>,
+    webhook_deliveries: Vec<WebhookDelivery>,
+}
+
+// Implement an in-memory database backend, mainly for use in tests.
+// Updates are applied to a copy of the basket, which is only stored
+// if the update succeeds, so failed updates are rolled back.
+#[derive(Debug, Default)]
+pub struct MemoryDatabase(Mutex<State>);
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // A panic in an update callback poisons the mutex, but since nothing
+    // is stored until the callback returns, the data is still consistent.
+    fn state(&self) -> MutexGuard<State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Every message which has been added to the outbox, for inspection in tests
+    pub fn outbox_messages(&self) -> Vec<OutboxMessage> {
+        self.state().outbox.clone()
+    }
+}
+
+// Implement all the operations supported by the database
+impl Database for MemoryDatabase {
+    fn get_basket(&self, basket_id: Uuid) -> DatabaseResult<Option<Basket>> {
+        Ok(self.state().baskets.get(&basket_id).cloned())
+    }
+    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut Basket, &mut Outbox) -> bool) -> DatabaseResult<Basket> {
+        // Hold the lock for the whole update, to behave like a transaction
+        let mut state = self.state();
+
+        // Create a new basket if none exists
+        let mut basket = state.baskets.get(&basket_id).cloned().unwrap_or_else(|| {
+            Basket {
+                id: basket_id,
+                contents: Default::default(),
+                version: 0
+            }
+        });
+        let old_version = basket.version;
+
+        // Run the update on the basket, discarding it if it fails
+        let mut outbox = Outbox::new();
+        if !f(&mut basket, &mut outbox) {
+            return Err(DatabaseError::RolledBack);
+        }
+
+        // Every successful update bumps the version
+        basket.version = old_version + 1;
+
+        // Store the outbox messages, skipping any duplicates
+        for row in outbox.into_rows(basket_id, basket.version) {
+            if state.outbox.iter().any(|m| m.idempotency_key == row.idempotency_key) {
+                continue;
+            }
+            let id = state.outbox.len() as i64 + 1;
+            state.outbox.push(OutboxMessage {
+                id,
+                idempotency_key: row.idempotency_key,
+                topic: row.topic,
+                basket_id: row.basket_id,
+                payload: row.payload,
+                created_at: row.created_at,
+                attempts: 0,
+                next_attempt_at: row.next_attempt_at,
+                last_error: None,
+                delivered_at: None,
+                abandoned_at: None,
+            });
+        }
+
+        state.baskets.insert(basket_id, basket.clone());
+        Ok(basket)
+    }
+    fn migrate(&self) -> DatabaseResult<()> {
+        Ok(())
+    }
+    fn claim_outbox_messages(&self, limit: usize, lease: chrono::Duration) -> DatabaseResult<Vec<OutboxMessage>> {
+        let mut state = self.state();
+        let now = Utc::now();
+        let mut due: Vec<_> = state.outbox.iter_mut()
+            .filter(|m| m.is_pending() && m.next_attempt_at <= now)
+            .collect();
+        due.sort_by_key(|m| m.next_attempt_at);
+        Ok(due.into_iter().take(limit).map(|m| {
+            m.next_attempt_at = now + lease;
+            m.clone()
+        }).collect())
+    }
+    fn complete_outbox_message(&self, id: i64) -> DatabaseResult<()> {
+        let mut state = self.state();
+        let message = state.outbox.iter_mut().find(|m| m.id == id)
+            .ok_or(DatabaseError::NotFound)?;
+        message.delivered_at = Some(Utc::now());
+        Ok(())
+    }
+    fn retry_outbox_message(&self, id: i64, error: &str, next_attempt_at: Option<DateTime<Utc>>) -> DatabaseResult<()> {
+        let mut state = self.state();
+        let message = state.outbox.iter_mut().find(|m| m.id == id)
+            .ok_or(DatabaseError::NotFound)?;
+        message.attempts += 1;
+        message.last_error = Some(error.into());
+        match next_attempt_at {
+            Some(next_attempt_at) => message.next_attempt_at = next_attempt_at,
+            None => message.abandoned_at = Some(Utc::now()),
+        }
+        Ok(())
+    }
+    fn insert_webhook(&self, webhook: &Webhook) -> DatabaseResult<()> {
+        self.state().webhooks.push(webhook.clone());
+        Ok(())
+    }
+    fn delete_webhook(&self, webhook_id: Uuid) -> DatabaseResult<Option<Webhook>> {
+        let mut state = self.state();
+        state.webhook_deliveries.retain(|d| d.webhook_id != webhook_id);
+        let index = state.webhooks.iter().position(|w| w.id == webhook_id);
+        Ok(index.map(|index| state.webhooks.remove(index)))
+    }
+    fn get_webhooks(&self, tenant_id: &str) -> DatabaseResult<Vec<Webhook>> {
+        Ok(self.state().webhooks.iter()
+            .filter(|w| w.tenant_id == tenant_id)
+            .cloned()
+            .collect())
+    }
+    fn insert_webhook_delivery(&self, delivery: &WebhookDelivery) -> DatabaseResult<()> {
+        let mut state = self.state();
+        if !state.webhooks.iter().any(|w| w.id == delivery.webhook_id) {
+            return Err(DatabaseError::NotFound);
+        }
+        state.webhook_deliveries.push(delivery.clone());
+        Ok(())
+    }
+    fn get_webhook_deliveries(&self, webhook_id: Uuid, limit: usize) -> DatabaseResult<Vec<WebhookDelivery>> {
+        Ok(self.state().webhook_deliveries.iter()
+            .rev()
+            .filter(|d| d.webhook_id == webhook_id)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+    fn has_webhook_succeeded(&self, webhook_id: Uuid, event_id: &str) -> DatabaseResult<bool> {
+        Ok(self.state().webhook_deliveries.iter()
+            .any(|d| d.webhook_id == webhook_id && d.event_id == event_id && d.error.is_none()))
+    }
+}
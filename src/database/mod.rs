@@ -1,3 +1,4 @@
 pub mod middleware;
 pub mod interface;
 pub mod postgres;
+pub mod memory;
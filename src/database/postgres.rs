@@ -5,13 +5,17 @@ use std::fmt;
 use diesel;
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
-use diesel::result::QueryResult;
+use diesel::pg::upsert::*;
+use diesel::result::{Error as DieselError, DatabaseErrorKind};
 use r2d2;
 use r2d2_diesel::ConnectionManager;
 use uuid::Uuid;
+use chrono::{self, DateTime, Utc};
 
 use schema::*;
-use database::interface::Database;
+use outbox::{Outbox, OutboxMessage};
+use webhooks::{Webhook, WebhookDelivery};
+use database::interface::{Database, DatabaseError, DatabaseResult};
 
 embed_migrations!("migrations");
 
@@ -34,70 +38,233 @@ impl PgDatabase {
     }
 
     // Run some code in a transaction, and retry it automatically
-    // if the transaction is rolled back.
-    fn execute<R, F>(&self, mut f: F) -> R where F: FnMut(&PgConnection) -> QueryResult<R> {
+    // if the transaction conflicts with another.
+    fn execute<R, F>(&self, mut f: F) -> DatabaseResult<R> where F: FnMut(&PgConnection) -> DatabaseResult<R> {
         use std::thread;
         use std::time::Duration;
 
         // Get a connection from the pool
         let conn = self.0.get()
-            .expect("Failed to obtain database connection");
+            .map_err(|_| DatabaseError::PoolTimeout)?;
 
         let mut num_attempts = 0;
         loop {
             // Try running the code in a transaction
             match conn.transaction(|| f(&conn)) {
                 // Success, return result
-                Ok(r) => break r,
-                // Error, retry 5 times with backoff
-                Err(_) if num_attempts < 5 => {
+                Ok(r) => break Ok(r),
+                // Conflict, retry 5 times with backoff
+                Err(ref e) if e.is_retryable() && num_attempts < 5 => {
                     thread::sleep(Duration::from_millis(10 << num_attempts));
                     num_attempts += 1;
                 },
-                // Any other error, panic
-                Err(e) => panic!("Database error: {}", e)
+                // Any other error, give up
+                Err(e) => break Err(e)
             }
         }
     }
 }
 
+impl From<DieselError> for DatabaseError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => DatabaseError::NotFound,
+            DieselError::DeserializationError(e) => DatabaseError::CorruptData(e.to_string()),
+            DieselError::RollbackTransaction => DatabaseError::RolledBack,
+            // Conflicts which are worth retrying are detected where they
+            // can happen, so that genuine constraint violations are not
+            // retried.
+            other => DatabaseError::Query(other.to_string())
+        }
+    }
+}
+
+// Find an existing basket if one exists
+fn find_basket(conn: &PgConnection, basket_id: Uuid) -> DatabaseResult<Option<Basket>> {
+    match baskets::table.find(basket_id).first::<Basket>(conn) {
+        Ok(basket) => Ok(Some(basket)),
+        Err(DieselError::NotFound) => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
 // Implement all the operations supported by the database
 impl Database for PgDatabase {
-    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut Basket)) -> Basket {
+    fn get_basket(&self, basket_id: Uuid) -> DatabaseResult<Option<Basket>> {
         self.execute(|conn| {
-            // Find an existing basket if one exists
-            let maybe_basket = baskets::table.find(basket_id).first::<Basket>(conn);
-            let is_new_basket = maybe_basket.is_err();
+            // Guarantee that reads never modify the database
+            conn.execute("SET TRANSACTION READ ONLY")?;
+            find_basket(conn, basket_id)
+        })
+    }
+    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut Basket, &mut Outbox) -> bool) -> DatabaseResult<Basket> {
+        self.execute(|conn| {
+            let mut outbox = Outbox::new();
+            let maybe_basket = find_basket(conn, basket_id)?;
+            let is_new_basket = maybe_basket.is_none();
 
             // Create a new basket if none exists
-            let mut basket = maybe_basket.unwrap_or_else(|_| {
+            let mut basket = maybe_basket.unwrap_or_else(|| {
                 Basket {
                     id: basket_id,
-                    contents: Default::default()
+                    contents: Default::default(),
+                    version: 0
                 }
             });
+            let old_version = basket.version;
+
+            // Run the update on the basket, rolling back if it fails
+            if !f(&mut basket, &mut outbox) {
+                return Err(DatabaseError::RolledBack);
+            }
 
-            // Run the update on the basket
-            f(&mut basket);
+            // Every successful update bumps the version
+            basket.version = old_version + 1;
 
-            // Update the database  
+            // Update the database
             if is_new_basket {
-                basket = diesel::insert(&basket).into(baskets::table)
-                    .get_result::<Basket>(conn)?;
+                // If another transaction created the basket first, retry
+                // so that this update is applied on top of it.
+                basket = match diesel::insert(&basket).into(baskets::table)
+                    .get_result::<Basket>(conn)
+                {
+                    Ok(basket) => basket,
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        return Err(DatabaseError::SerializationConflict)
+                    },
+                    Err(e) => return Err(e.into())
+                };
             } else {
-                basket = diesel::update(baskets::table.find(basket_id))
-                    .set(baskets::contents.eq(basket.contents))
-                    .get_result::<Basket>(conn)?;
+                // Only write the basket if nobody else has changed it
+                // since we read it, otherwise retry from the start.
+                let target = baskets::table
+                    .filter(baskets::id.eq(basket_id))
+                    .filter(baskets::version.eq(old_version));
+                basket = match diesel::update(target)
+                    .set((baskets::contents.eq(basket.contents), baskets::version.eq(basket.version)))
+                    .get_result::<Basket>(conn)
+                {
+                    Ok(basket) => basket,
+                    Err(DieselError::NotFound) => return Err(DatabaseError::SerializationConflict),
+                    Err(e) => return Err(e.into())
+                };
+            }
+
+            // Record any side effects in the same transaction. A message
+            // whose key has already been used is a duplicate.
+            if !outbox.is_empty() {
+                let rows = outbox.into_rows(basket_id, basket.version);
+                diesel::insert(&rows.on_conflict_do_nothing()).into(outbox::table)
+                    .execute(conn)?;
             }
 
             // Return the updated basket
             Ok(basket)
         })
     }
-    fn migrate(&self) {
+    fn migrate(&self) -> DatabaseResult<()> {
         let conn = self.0.get()
-            .expect("Failed to obtain database connection");
+            .map_err(|_| DatabaseError::PoolTimeout)?;
         embedded_migrations::run_with_output(&*conn, &mut io::stdout())
-            .expect("Failed to run migration");
+            .map_err(|e| DatabaseError::MigrationFailed(e.to_string()))
+    }
+    fn claim_outbox_messages(&self, limit: usize, lease: chrono::Duration) -> DatabaseResult<Vec<OutboxMessage>> {
+        self.execute(|conn| {
+            let now = Utc::now();
+            let due = outbox::table
+                .select(outbox::id)
+                .filter(outbox::delivered_at.is_null())
+                .filter(outbox::abandoned_at.is_null())
+                .filter(outbox::next_attempt_at.le(now))
+                .order(outbox::next_attempt_at)
+                .limit(limit as i64)
+                .load::<i64>(conn)?;
+
+            // Checking the due date again means that if another relay
+            // claimed a message first, it is skipped here.
+            let target = outbox::table
+                .filter(outbox::id.eq_any(due))
+                .filter(outbox::next_attempt_at.le(now));
+            Ok(diesel::update(target)
+                .set(outbox::next_attempt_at.eq(now + lease))
+                .get_results::<OutboxMessage>(conn)?)
+        })
+    }
+    fn complete_outbox_message(&self, id: i64) -> DatabaseResult<()> {
+        self.execute(|conn| {
+            diesel::update(outbox::table.find(id))
+                .set(outbox::delivered_at.eq(Utc::now()))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+    fn retry_outbox_message(&self, id: i64, error: &str, next_attempt_at: Option<DateTime<Utc>>) -> DatabaseResult<()> {
+        self.execute(|conn| {
+            diesel::update(outbox::table.find(id))
+                .set((outbox::attempts.eq(outbox::attempts + 1), outbox::last_error.eq(error)))
+                .execute(conn)?;
+            match next_attempt_at {
+                Some(next_attempt_at) => diesel::update(outbox::table.find(id))
+                    .set(outbox::next_attempt_at.eq(next_attempt_at))
+                    .execute(conn)?,
+                None => diesel::update(outbox::table.find(id))
+                    .set(outbox::abandoned_at.eq(Utc::now()))
+                    .execute(conn)?,
+            };
+            Ok(())
+        })
+    }
+    fn insert_webhook(&self, webhook: &Webhook) -> DatabaseResult<()> {
+        self.execute(|conn| {
+            diesel::insert(webhook).into(webhooks::table).execute(conn)?;
+            Ok(())
+        })
+    }
+    fn delete_webhook(&self, webhook_id: Uuid) -> DatabaseResult<Option<Webhook>> {
+        self.execute(|conn| {
+            // Deliveries are removed by the foreign key cascade
+            match diesel::delete(webhooks::table.find(webhook_id)).get_result::<Webhook>(conn) {
+                Ok(webhook) => Ok(Some(webhook)),
+                Err(DieselError::NotFound) => Ok(None),
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+    fn get_webhooks(&self, tenant_id: &str) -> DatabaseResult<Vec<Webhook>> {
+        self.execute(|conn| {
+            conn.execute("SET TRANSACTION READ ONLY")?;
+            Ok(webhooks::table
+                .filter(webhooks::tenant_id.eq(tenant_id))
+                .order(webhooks::created_at)
+                .load::<Webhook>(conn)?)
+        })
+    }
+    fn insert_webhook_delivery(&self, delivery: &WebhookDelivery) -> DatabaseResult<()> {
+        self.execute(|conn| {
+            diesel::insert(delivery).into(webhook_deliveries::table).execute(conn)?;
+            Ok(())
+        })
+    }
+    fn get_webhook_deliveries(&self, webhook_id: Uuid, limit: usize) -> DatabaseResult<Vec<WebhookDelivery>> {
+        self.execute(|conn| {
+            conn.execute("SET TRANSACTION READ ONLY")?;
+            Ok(webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+                .order(webhook_deliveries::attempted_at.desc())
+                .limit(limit as i64)
+                .load::<WebhookDelivery>(conn)?)
+        })
+    }
+    fn has_webhook_succeeded(&self, webhook_id: Uuid, event_id: &str) -> DatabaseResult<bool> {
+        self.execute(|conn| {
+            conn.execute("SET TRANSACTION READ ONLY")?;
+            let successes = webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+                .filter(webhook_deliveries::event_id.eq(event_id))
+                .filter(webhook_deliveries::error.is_null())
+                .count()
+                .get_result::<i64>(conn)?;
+            Ok(successes > 0)
+        })
     }
 }
@@ -0,0 +1,44 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde_json;
+
+use dispatch::{Message, Transport, TransportError};
+
+
+// Appends each message to a file as a line of JSON instead of sending
+// it anywhere. Useful for development and testing.
+#[derive(Debug)]
+pub struct FileSinkTransport {
+    path: PathBuf,
+    // Stops concurrent sends from interleaving their output
+    lock: Mutex<()>,
+}
+
+impl FileSinkTransport {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileSinkTransport {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Transport for FileSinkTransport {
+    fn name(&self) -> &str {
+        "file_sink"
+    }
+
+    fn send(&self, message: &Message) -> Result<(), TransportError> {
+        let mut line = serde_json::to_string(message)
+            .map_err(|e| TransportError::new(e.to_string()))?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Read;
+use std::time::Duration;
+
+use hyper::Client;
+use hyper::header::{Authorization, Bearer, ContentType};
+use serde_json;
+
+use dispatch::{Message, Transport, TransportError};
+
+
+// The request body posted to the gateway
+#[derive(Serialize)]
+struct SmsRequest<'a> {
+    to: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<&'a str>,
+    body: &'a str,
+    // Lets the gateway discard duplicate submissions
+    reference: String,
+}
+
+// Sends SMS messages by posting JSON to an HTTP gateway. Any 2xx
+// response is taken to mean the message was accepted.
+#[derive(Debug, Clone)]
+pub struct HttpSmsTransport {
+    url: String,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl HttpSmsTransport {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        HttpSmsTransport {
+            url: url.into(),
+            token: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    // Send the token as a bearer token with each request
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Transport for HttpSmsTransport {
+    fn name(&self) -> &str {
+        "http_sms"
+    }
+
+    fn send(&self, message: &Message) -> Result<(), TransportError> {
+        let body = serde_json::to_string(&SmsRequest {
+            to: &message.to,
+            from: message.from.as_ref().map(|from| &**from),
+            body: &message.body,
+            reference: message.communication_id.to_string(),
+        }).map_err(|e| TransportError::new(e.to_string()))?;
+
+        let mut client = Client::new();
+        client.set_read_timeout(Some(self.timeout));
+        client.set_write_timeout(Some(self.timeout));
+        let mut request = client.post(&self.url)
+            .header(ContentType::json())
+            .body(&*body);
+        if let Some(ref token) = self.token {
+            request = request.header(Authorization(Bearer { token: token.clone() }));
+        }
+        let mut response = request.send()
+            .map_err(|e| TransportError::new(format!("SMS gateway request failed: {}", e)))?;
+
+        if !response.status.is_success() {
+            let mut text = String::new();
+            let _ = response.read_to_string(&mut text);
+            return Err(TransportError::new(format!("SMS gateway replied {}: {}", response.status, text.trim())));
+        }
+        Ok(())
+    }
+}
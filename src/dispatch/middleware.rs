@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::ops::Deref;
+
+use iron::prelude::*;
+use iron::{typemap, BeforeMiddleware};
+
+use dispatch::Dispatcher;
+
+// Provides the dispatcher to all request handlers via the
+// `.dispatcher()` method, in the same way as the database.
+
+// Shared reference to a dispatcher
+#[derive(Clone, Debug)]
+pub struct DispatcherWrapper(Arc<Dispatcher>);
+
+impl DispatcherWrapper {
+    pub fn new<D: Into<Arc<Dispatcher>>>(dispatcher: D) -> Self {
+        DispatcherWrapper(dispatcher.into())
+    }
+}
+
+impl Deref for DispatcherWrapper {
+    type Target = Dispatcher;
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl typemap::Key for DispatcherWrapper { type Value = Self; }
+
+impl BeforeMiddleware for DispatcherWrapper {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Self>(self.clone());
+        Ok(())
+    }
+}
+
+pub trait DispatcherRequestExt {
+    fn dispatcher(&self) -> DispatcherWrapper;
+}
+
+impl<'a, 'b> DispatcherRequestExt for Request<'a, 'b> {
+    fn dispatcher(&self) -> DispatcherWrapper {
+        self.extensions.get::<DispatcherWrapper>()
+            .expect("DispatcherMiddleware not registered")
+            .clone()
+    }
+}
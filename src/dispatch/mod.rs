@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::io;
+
+use chrono::{self, Utc};
+use serde_json;
+use uuid::Uuid;
+
+use database::interface::Database;
+use error::ApiError;
+use outbox::{Outbox, OutboxHandler, OutboxMessage};
+use webhooks::{self, WebhookEvent};
+use schema::*;
+
+pub mod middleware;
+pub mod template;
+pub mod smtp;
+pub mod http_sms;
+pub mod file_sink;
+pub mod registry;
+
+use self::registry::{TemplateRegistry, DEFAULT_LOCALE, PRIVATE_PREFIX};
+
+
+// A fully rendered message, ready to be handed to a transport
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub communication_id: Uuid,
+    // An email address or E.164 phone number, depending on the transport
+    pub to: String,
+    // The name the message appears to be from
+    pub from: Option<String>,
+    pub bcc: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    // The locale of the template which was used
+    pub locale: String,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct TransportError(String);
+
+impl TransportError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        TransportError(message.into())
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for TransportError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError(e.to_string())
+    }
+}
+
+
+// Transport must:
+// - be thread-safe (Send + Sync)
+// - live as long as required ('static)
+pub trait Transport: Send + Sync + 'static + Debug {
+    // A short name recorded against each delivery attempt
+    fn name(&self) -> &str;
+    fn send(&self, message: &Message) -> Result<(), TransportError>;
+}
+
+
+// Topic of the outbox messages which ask for a communication to be sent
+pub const COMMUNICATION_QUEUED: &str = "communication.queued";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CommunicationQueued {
+    basket_id: Uuid,
+    communication_id: Uuid,
+}
+
+// Ask the relay to send a communication once the current change
+// to the basket has been committed.
+pub fn enqueue(outbox: &mut Outbox, basket_id: Uuid, communication_id: Uuid) {
+    let payload = serde_json::to_value(CommunicationQueued { basket_id, communication_id })
+        .expect("Failed to serialize outbox message");
+    outbox.push(COMMUNICATION_QUEUED, payload);
+}
+
+
+// Find a communication in a basket, along with its recipient
+fn find_communication(basket: &Basket, communication_id: Uuid) -> Result<(&Communication, &Recipient), ApiError> {
+    let contents = &basket.contents.0;
+    let communication = contents.communications.iter()
+        .find(|c| c.id == communication_id)
+        .ok_or_else(|| ApiError::not_found("Communication ID not found"))?;
+    let recipient = contents.recipients.iter()
+        .find(|r| r.id == communication.recipient)
+        .ok_or_else(|| ApiError::not_found("Recipient ID not found"))?;
+    Ok((communication, recipient))
+}
+
+
+// Sends communications using the transport which matches
+// each recipient's contact method.
+#[derive(Debug)]
+pub struct Dispatcher {
+    email: Option<Box<Transport>>,
+    sms: Option<Box<Transport>>,
+    templates: TemplateRegistry,
+    // Used when a communication does not say who it is from
+    default_sender: String,
+    // How long a communication is claimed for while it is being sent
+    send_lease: chrono::Duration,
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Dispatcher {
+            email: None,
+            sms: None,
+            templates: TemplateRegistry::default(),
+            default_sender: "Checkout".into(),
+            send_lease: chrono::Duration::minutes(10),
+        }
+    }
+}
+
+impl Dispatcher {
+    // A dispatcher with no transports, which fails every delivery
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn email_transport<T: Transport>(mut self, transport: T) -> Self {
+        self.email = Some(Box::new(transport));
+        self
+    }
+
+    pub fn sms_transport<T: Transport>(mut self, transport: T) -> Self {
+        self.sms = Some(Box::new(transport));
+        self
+    }
+
+    pub fn templates(mut self, templates: TemplateRegistry) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    pub fn default_sender<S: Into<String>>(mut self, sender: S) -> Self {
+        self.default_sender = sender.into();
+        self
+    }
+
+    // Should be longer than the slowest transport takes to send a message
+    pub fn send_lease(mut self, lease: chrono::Duration) -> Self {
+        self.send_lease = lease;
+        self
+    }
+
+    // Render a communication to a recipient. Private args take precedence
+    // over public args, since they are set by the server.
+    pub fn render(&self, basket: &Basket, recipient: &Recipient, communication: &Communication) -> Result<Message, String> {
+        let public_args = &communication.public_args;
+        let private_args = &communication.private_args;
+        let from = private_args.from.clone().or_else(|| public_args.from.clone());
+        let mut bcc = public_args.bcc.clone();
+        for address in &private_args.bcc {
+            if !bcc.contains(address) {
+                bcc.push(address.clone());
+            }
+        }
+        let locale = private_args.locale.as_ref()
+            .or(public_args.locale.as_ref())
+            .map(|locale| &**locale)
+            .unwrap_or(DEFAULT_LOCALE);
+        let (locale, template) = self.templates.get(communication.kind, locale);
+
+        let to = match recipient.contact_method {
+            ContactMethod::Email { ref address } => address.clone(),
+            ContactMethod::Sms { ref phone_number } => phone_number.clone(),
+        };
+        let mut variables = BTreeMap::new();
+        variables.insert("basket_id".to_string(), basket.id.to_string());
+        variables.insert("recipient_id".to_string(), recipient.id.to_string());
+        variables.insert("recipient_name".to_string(), recipient.name.clone());
+        variables.insert("recipient_contact".to_string(), to.clone());
+        variables.insert("sender".to_string(), from.clone().unwrap_or_else(|| self.default_sender.clone()));
+        variables.insert("communication_id".to_string(), communication.id.to_string());
+        variables.insert("locale".to_string(), locale.to_string());
+        for (name, value) in &private_args.variables {
+            variables.insert(format!("{}{}", PRIVATE_PREFIX, name), value.clone());
+        }
+
+        Ok(Message {
+            communication_id: communication.id,
+            to,
+            from,
+            bcc,
+            subject: template.subject.render(&variables).map_err(|e| e.to_string())?,
+            body: template.body.render(&variables).map_err(|e| e.to_string())?,
+            locale: locale.to_string(),
+        })
+    }
+
+    // Render a communication in a basket without sending it
+    pub fn preview(&self, basket: &Basket, communication_id: Uuid) -> Result<Message, ApiError> {
+        let (communication, recipient) = find_communication(basket, communication_id)?;
+        self.render(basket, recipient, communication)
+            .map_err(ApiError::invalid_input)
+    }
+
+    fn transport_for(&self, contact_method: &ContactMethod) -> Result<&Transport, String> {
+        let (transport, kind) = match *contact_method {
+            ContactMethod::Email { .. } => (&self.email, "email"),
+            ContactMethod::Sms { .. } => (&self.sms, "SMS"),
+        };
+        transport.as_ref()
+            .map(|transport| &**transport)
+            .ok_or_else(|| format!("No transport is configured for {}", kind))
+    }
+
+    // Send a queued communication, and record the outcome against it.
+    // The communication is claimed in its own transaction before it is
+    // sent, so that concurrent calls cannot both send it. If the outcome
+    // cannot be recorded, the claim expires and it may be sent again.
+    pub fn dispatch(&self, db: &Database, basket_id: Uuid, communication_id: Uuid) -> Result<Basket, ApiError> {
+        let now = Utc::now();
+        let claimed_until = now + self.send_lease;
+        let basket = db.update_basket(basket_id, &mut |basket: &mut Basket| {
+            basket.contents.0.find_communication_mut(communication_id)?.claim(now, claimed_until)
+        })?;
+        let (communication, recipient) = find_communication(&basket, communication_id)?;
+
+        let mut transport_name = None;
+        let result = self.transport_for(&recipient.contact_method).and_then(|transport| {
+            transport_name = Some(transport.name().to_string());
+            let message = self.render(&basket, recipient, communication)?;
+            transport.send(&message).map_err(|e| e.to_string())
+        });
+        let attempt = DeliveryAttempt {
+            attempted_at: Utc::now(),
+            transport: transport_name,
+            error: result.err(),
+        };
+
+        db.update_basket_with_outbox(basket_id, &mut |basket: &mut Basket, outbox: &mut Outbox| {
+            let sent = basket.contents.0.find_communication_mut(communication_id)?
+                .record_attempt(claimed_until, attempt.clone());
+            if sent {
+                webhooks::emit(outbox, &basket.contents.0, basket_id, WebhookEvent::CommunicationSent, json!({
+                    "communication_id": communication_id,
+                    "recipient_id": recipient.id,
+                }));
+            }
+            Ok(())
+        })
+    }
+}
+
+
+// Sends communications queued via the outbox. When a delivery fails
+// the error is returned, so that the relay tries again later.
+impl OutboxHandler for Dispatcher {
+    fn handle(&self, db: &Database, message: &OutboxMessage) -> Result<(), String> {
+        let queued: CommunicationQueued = serde_json::from_value(message.payload.clone())
+            .map_err(|e| e.to_string())?;
+        let status = |basket: &Basket| {
+            basket.contents.0.communications.iter()
+                .find(|c| c.id == queued.communication_id)
+                .map(|c| (c.status, c.delivery_attempts.last().and_then(|a| a.error.clone())))
+        };
+
+        let basket = db.get_basket(queued.basket_id).map_err(|e| e.to_string())?;
+        match basket.as_ref().and_then(&status) {
+            // A communication which is already being sent is only
+            // dispatched again if its claim has expired
+            Some((CommunicationStatus::Queued, _)) |
+            Some((CommunicationStatus::Sending, _)) => {},
+            // The previous attempt failed, so queue it again
+            Some((CommunicationStatus::Failed, _)) => {
+                db.update_basket(queued.basket_id, &mut |basket: &mut Basket| {
+                    basket.contents.0.find_communication_mut(queued.communication_id)?
+                        .set_status(CommunicationStatus::Queued)
+                }).map_err(|e: ApiError| e.to_string())?;
+            },
+            // Already sent, cancelled or removed, so there is nothing to do
+            _ => return Ok(())
+        }
+
+        let basket = self.dispatch(db, queued.basket_id, queued.communication_id)
+            .map_err(|e| e.to_string())?;
+        match status(&basket) {
+            Some((CommunicationStatus::Failed, error)) => Err(error.unwrap_or_else(|| "Delivery failed".into())),
+            _ => Ok(())
+        }
+    }
+}
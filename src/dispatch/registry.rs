@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use schema::CommunicationKind;
+
+use dispatch::template::{Template, TemplateError};
+
+
+// The name used for each kind of communication in template file names
+const KINDS: [(CommunicationKind, &str); 3] = [
+    (CommunicationKind::Invite, "invite"),
+    (CommunicationKind::Reminder, "reminder"),
+    (CommunicationKind::CompletionNotice, "completion_notice"),
+];
+
+// The locale of the built-in templates, and the default locale
+// when a communication does not specify one.
+pub const DEFAULT_LOCALE: &str = "en";
+
+// The variables provided to every template by `Dispatcher::render`
+pub const VARIABLES: [&str; 7] = [
+    "basket_id",
+    "recipient_id",
+    "recipient_name",
+    "recipient_contact",
+    "sender",
+    "communication_id",
+    "locale",
+];
+
+// Variables taken from a communication's private args are named
+// `private.<name>`. Which of those exist is only known when rendering.
+pub const PRIVATE_PREFIX: &str = "private.";
+
+pub fn is_known_variable(name: &str) -> bool {
+    VARIABLES.contains(&name) || (name.starts_with(PRIVATE_PREFIX) && name.len() > PRIVATE_PREFIX.len())
+}
+
+fn is_letter(c: char) -> bool {
+    match c {
+        'a'...'z' | 'A'...'Z' => true,
+        _ => false
+    }
+}
+
+// Normalise a locale of the form `language[-REGION]`, eg. `en_gb` becomes
+// `en-GB`. The region may also be a three digit area code, eg. `es-419`.
+pub fn normalize_locale(locale: &str) -> Option<String> {
+    let mut parts = locale.trim().split(|c| c == '-' || c == '_');
+    let language = parts.next().unwrap_or("");
+    if language.len() < 2 || language.len() > 3 || !language.chars().all(is_letter) {
+        return None;
+    }
+    let mut result = language.to_lowercase();
+    if let Some(region) = parts.next() {
+        let valid = match region.len() {
+            2 => region.chars().all(is_letter),
+            3 => region.chars().all(|c| c.is_digit(10)),
+            _ => false
+        };
+        if !valid {
+            return None;
+        }
+        result.push('-');
+        result.push_str(&region.to_uppercase());
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(result)
+}
+
+
+// The subject and body used for a kind of communication. The subject
+// is not used for SMS.
+#[derive(Debug, Clone)]
+pub struct MessageTemplate {
+    pub subject: Template,
+    pub body: Template,
+}
+
+impl MessageTemplate {
+    fn builtin(kind: CommunicationKind) -> MessageTemplate {
+        let (subject, body) = match kind {
+            CommunicationKind::Invite => (
+                "{{sender}} has asked you for some information",
+                "Hello {{recipient_name}},\n\n{{sender}} has asked you to provide some information to complete their checks.\n"
+            ),
+            CommunicationKind::Reminder => (
+                "Reminder: {{sender}} is waiting for some information",
+                "Hello {{recipient_name}},\n\n{{sender}} is still waiting for you to provide some information.\n"
+            ),
+            CommunicationKind::CompletionNotice => (
+                "Thank you for your information",
+                "Hello {{recipient_name}},\n\nThank you, {{sender}} has received everything they need.\n"
+            ),
+        };
+        MessageTemplate {
+            subject: subject.parse().expect("Invalid built-in template"),
+            body: body.parse().expect("Invalid built-in template"),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io { path: PathBuf, error: io::Error },
+    Template { path: PathBuf, error: TemplateError },
+    // A directory which is not named after a locale
+    InvalidLocale { path: PathBuf },
+    // A file which is not named `<kind>.subject` or `<kind>.body`
+    UnexpectedFile { path: PathBuf },
+    // Only one of the subject and body was provided
+    Incomplete { path: PathBuf },
+    // A template refers to a variable which is never provided
+    UnknownVariable { path: PathBuf, name: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+            LoadError::Template { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+            LoadError::InvalidLocale { ref path } => write!(f, "{}: Not a valid locale", path.display()),
+            LoadError::UnexpectedFile { ref path } => write!(f, "{}: Expected `<kind>.subject` or `<kind>.body`", path.display()),
+            LoadError::Incomplete { ref path } => write!(f, "{}: Both a subject and a body are required", path.display()),
+            LoadError::UnknownVariable { ref path, ref name } => write!(f, "{}: Unknown variable `{}`", path.display(), name),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        "failed to load templates"
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, LoadError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|error| LoadError::Io { path: path.into(), error })?;
+    Ok(contents)
+}
+
+fn read_dir(path: &Path) -> Result<Vec<PathBuf>, LoadError> {
+    let io_error = |error: io::Error| LoadError::Io { path: path.into(), error };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path).map_err(&io_error)? {
+        let path = entry.map_err(&io_error)?.path();
+        // Skip hidden files such as `.gitkeep`
+        let hidden = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with('.'))
+            .unwrap_or(false);
+        if !hidden {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+
+// Message templates for each kind of communication and locale.
+// The built-in templates are always available as a fallback.
+#[derive(Debug, Clone)]
+pub struct TemplateRegistry {
+    templates: HashMap<(CommunicationKind, String), MessageTemplate>,
+}
+
+impl Default for TemplateRegistry {
+    fn default() -> Self {
+        TemplateRegistry {
+            templates: KINDS.iter()
+                .map(|&(kind, _)| ((kind, DEFAULT_LOCALE.to_string()), MessageTemplate::builtin(kind)))
+                .collect()
+        }
+    }
+}
+
+impl TemplateRegistry {
+    // A registry containing only the built-in templates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Load templates from a directory laid out as `<locale>/<kind>.subject`
+    // and `<locale>/<kind>.body`, eg. `en-GB/invite.subject`. These take
+    // precedence over the built-in templates.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, LoadError> {
+        let mut registry = Self::default();
+        for locale_dir in read_dir(dir.as_ref())? {
+            let locale = locale_dir.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(normalize_locale)
+                .ok_or_else(|| LoadError::InvalidLocale { path: locale_dir.clone() })?;
+
+            let mut subjects = HashMap::new();
+            let mut bodies = HashMap::new();
+            for path in read_dir(&locale_dir)? {
+                let (kind, is_subject) = {
+                    let mut parts = path.file_name()
+                        .and_then(|name| name.to_str())
+                        .unwrap_or("")
+                        .splitn(2, '.');
+                    let name = parts.next().unwrap_or("");
+                    let kind = KINDS.iter().find(|&&(_, n)| n == name).map(|&(kind, _)| kind);
+                    match (kind, parts.next()) {
+                        (Some(kind), Some(part)) if part == "subject" || part == "body" => (kind, part == "subject"),
+                        _ => return Err(LoadError::UnexpectedFile { path: path.clone() })
+                    }
+                };
+                let template = Template::parse(&read_file(&path)?)
+                    .map_err(|error| LoadError::Template { path: path.clone(), error })?;
+                // Catch typos when loading, rather than failing every
+                // delivery which uses the template.
+                if let Some(name) = template.variables().into_iter().find(|name| !is_known_variable(name)) {
+                    return Err(LoadError::UnknownVariable { path: path.clone(), name: name.into() });
+                }
+                if is_subject {
+                    subjects.insert(kind, template);
+                } else {
+                    bodies.insert(kind, template);
+                }
+            }
+
+            for &(kind, name) in &KINDS {
+                match (subjects.remove(&kind), bodies.remove(&kind)) {
+                    (Some(subject), Some(body)) => {
+                        registry.insert(kind, &locale, MessageTemplate { subject, body });
+                    },
+                    (None, None) => {},
+                    _ => return Err(LoadError::Incomplete { path: locale_dir.join(name) })
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, kind: CommunicationKind, locale: &str, template: MessageTemplate) {
+        self.templates.insert((kind, locale.to_string()), template);
+    }
+
+    // Find the template for a kind of communication, falling back from
+    // eg. `en-GB` to `en`, and then to the default locale. Returns the
+    // locale of the template which was found.
+    pub fn get<'a>(&'a self, kind: CommunicationKind, locale: &'a str) -> (&'a str, &'a MessageTemplate) {
+        let language = locale.split('-').next().unwrap_or(locale);
+        for &candidate in &[locale, language, DEFAULT_LOCALE] {
+            if let Some(template) = self.templates.get(&(kind, candidate.to_string())) {
+                return (candidate, template);
+            }
+        }
+        unreachable!("Built-in templates are always present")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::io::Write;
+    use uuid::Uuid;
+
+    #[test]
+    fn locales() {
+        assert_eq!(normalize_locale("en").as_ref().map(|s| &**s), Some("en"));
+        assert_eq!(normalize_locale("en_gb").as_ref().map(|s| &**s), Some("en-GB"));
+        assert_eq!(normalize_locale("es-419").as_ref().map(|s| &**s), Some("es-419"));
+        assert_eq!(normalize_locale("e"), None);
+        assert_eq!(normalize_locale("en-GB-x"), None);
+        assert_eq!(normalize_locale("../en"), None);
+    }
+
+    #[test]
+    fn load_templates() {
+        let dir = env::temp_dir().join(format!("checkout-templates-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("fr")).unwrap();
+        for &(name, contents) in &[("invite.subject", "Bonjour {{recipient_name}}"), ("invite.body", "De la part de {{sender}}")] {
+            File::create(dir.join("fr").join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+        let registry = TemplateRegistry::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let registry = registry.unwrap();
+
+        let variables: BTreeMap<_, _> = vec![
+            ("recipient_name".to_string(), "Ada".to_string()),
+            ("sender".to_string(), "Acme".to_string()),
+        ].into_iter().collect();
+
+        let (locale, template) = registry.get(CommunicationKind::Invite, "fr-CA");
+        assert_eq!(locale, "fr");
+        assert_eq!(template.subject.render(&variables).unwrap(), "Bonjour Ada");
+
+        // Other kinds fall back to the built-in templates
+        let (locale, template) = registry.get(CommunicationKind::Reminder, "fr");
+        assert_eq!(locale, DEFAULT_LOCALE);
+        assert_eq!(template.subject.render(&variables).unwrap(), "Reminder: Acme is waiting for some information");
+    }
+
+    #[test]
+    fn unknown_variables() {
+        assert!(is_known_variable("recipient_name"));
+        assert!(is_known_variable("private.reference"));
+        assert!(!is_known_variable("private."));
+        assert!(!is_known_variable("recipient"));
+        for &kind in &[CommunicationKind::Invite, CommunicationKind::Reminder, CommunicationKind::CompletionNotice] {
+            let template = MessageTemplate::builtin(kind);
+            assert!(template.subject.variables().into_iter().chain(template.body.variables()).all(is_known_variable));
+        }
+
+        let dir = env::temp_dir().join(format!("checkout-templates-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("en")).unwrap();
+        for &(name, contents) in &[("reminder.subject", "Reminder"), ("reminder.body", "Hello {{recipent_name}}")] {
+            File::create(dir.join("en").join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+        let result = TemplateRegistry::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(LoadError::UnknownVariable { ref name, .. }) if name == "recipent_name" => {},
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn incomplete_templates() {
+        let dir = env::temp_dir().join(format!("checkout-templates-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("de")).unwrap();
+        File::create(dir.join("de").join("reminder.body")).unwrap();
+        let result = TemplateRegistry::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(LoadError::Incomplete { .. }) => {},
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+}
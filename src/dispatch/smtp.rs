@@ -0,0 +1,163 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use dispatch::{Message, Transport, TransportError};
+
+
+// Sends email to an SMTP relay. Only the plain SMTP dialogue is spoken:
+// there is no support for TLS or authentication, so this is intended
+// for a relay on a trusted network.
+#[derive(Debug, Clone)]
+pub struct SmtpTransport {
+    // `host:port` of the relay
+    address: String,
+    // Address which all mail is sent from. The `from` argument of a
+    // communication only changes the display name.
+    sender: String,
+    hello_name: String,
+    timeout: Duration,
+}
+
+impl SmtpTransport {
+    pub fn new<S: Into<String>, T: Into<String>>(address: S, sender: T) -> Self {
+        SmtpTransport {
+            address: address.into(),
+            sender: sender.into(),
+            hello_name: "localhost".into(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn hello_name<S: Into<String>>(mut self, hello_name: S) -> Self {
+        self.hello_name = hello_name.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn format_message(&self, message: &Message) -> String {
+        let from = match message.from {
+            Some(ref name) => format!("\"{}\" <{}>", header_value(name).replace('"', "'"), self.sender),
+            None => format!("<{}>", self.sender)
+        };
+        // Bcc recipients are deliberately left out of the headers
+        let mut result = format!(
+            "From: {}\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            from, message.to, header_value(&message.subject), Utc::now().to_rfc2822(),
+            message.communication_id, self.hello_name
+        );
+        for line in message.body.lines() {
+            // Lines starting with a dot must be escaped, or a line
+            // containing a single dot would end the message.
+            if line.starts_with('.') {
+                result.push('.');
+            }
+            result.push_str(line);
+            result.push_str("\r\n");
+        }
+        result.push_str(".\r\n");
+        result
+    }
+}
+
+// Prevent values from introducing extra headers
+fn header_value(value: &str) -> String {
+    value.replace('\r', " ").replace('\n', " ")
+}
+
+// Read a possibly multi-line reply, and check it has the expected code
+fn read_reply<R: BufRead>(reader: &mut R, expected: &[u16]) -> Result<(), TransportError> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(TransportError::new("SMTP server closed the connection"));
+        }
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| TransportError::new(format!("Unexpected SMTP reply: {}", line.trim_right())))?;
+        // A dash after the code means more lines follow
+        if line[3..].starts_with('-') {
+            continue;
+        }
+        return if expected.contains(&code) {
+            Ok(())
+        } else {
+            Err(TransportError::new(format!("SMTP server replied: {}", line.trim_right())))
+        };
+    }
+}
+
+fn command<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, command: &str, expected: &[u16]) -> Result<(), TransportError> {
+    write!(writer, "{}\r\n", command)?;
+    writer.flush()?;
+    read_reply(reader, expected)
+}
+
+impl Transport for SmtpTransport {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    fn send(&self, message: &Message) -> Result<(), TransportError> {
+        let stream = TcpStream::connect(&*self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        read_reply(&mut reader, &[220])?;
+        command(&mut reader, &mut writer, &format!("EHLO {}", self.hello_name), &[250])?;
+        command(&mut reader, &mut writer, &format!("MAIL FROM:<{}>", self.sender), &[250])?;
+        for recipient in Some(&message.to).into_iter().chain(&message.bcc) {
+            command(&mut reader, &mut writer, &format!("RCPT TO:<{}>", recipient), &[250, 251])?;
+        }
+        command(&mut reader, &mut writer, "DATA", &[354])?;
+        writer.write_all(self.format_message(message).as_bytes())?;
+        read_reply(&mut reader, &[250])?;
+
+        // The message has been accepted, so a failure to quit cleanly
+        // does not matter.
+        let _ = command(&mut reader, &mut writer, "QUIT", &[221]);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use uuid::Uuid;
+
+    #[test]
+    fn multi_line_replies() {
+        let mut reader = Cursor::new(&b"250-smtp.example.com\r\n250-8BITMIME\r\n250 OK\r\n"[..]);
+        assert!(read_reply(&mut reader, &[250]).is_ok());
+        let mut reader = Cursor::new(&b"550 No such user\r\n"[..]);
+        assert!(read_reply(&mut reader, &[250]).is_err());
+    }
+
+    #[test]
+    fn message_format() {
+        let transport = SmtpTransport::new("localhost:25", "noreply@example.com");
+        let message = Message {
+            communication_id: Uuid::nil(),
+            to: "ada@example.com".into(),
+            from: Some("Acme\r\nBcc: eve@example.com".into()),
+            bcc: vec!["audit@example.com".into()],
+            subject: "Hello".into(),
+            body: "First line\n.\nLast line".into(),
+            locale: "en".into(),
+        };
+        let formatted = transport.format_message(&message);
+        assert!(formatted.starts_with("From: \"Acme  Bcc: eve@example.com\" <noreply@example.com>\r\nTo: <ada@example.com>\r\n"));
+        assert!(!formatted.contains("audit@example.com"));
+        assert!(formatted.ends_with("\r\n\r\nFirst line\r\n..\r\nLast line\r\n.\r\n"));
+    }
+}
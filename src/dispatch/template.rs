@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    // A `{{` was not followed by a matching `}}`
+    Unclosed { offset: usize },
+    // A variable name was empty or contained unexpected characters
+    InvalidName { name: String },
+    // The template refers to a variable which was not provided
+    UnknownVariable { name: String },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::Unclosed { offset } => write!(f, "Unclosed `{{{{` at offset {}", offset),
+            TemplateError::InvalidName { ref name } => write!(f, "Invalid variable name `{}`", name),
+            TemplateError::UnknownVariable { ref name } => write!(f, "Unknown variable `{}`", name),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn description(&self) -> &str {
+        "template error"
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+// A template where `{{ name }}` is replaced by the value of the
+// variable `name`. There are no conditionals, loops or escaping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '.' => true,
+        _ => false
+    })
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, TemplateError> {
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while let Some(start) = source[offset..].find("{{") {
+            let start = offset + start;
+            if start > offset {
+                segments.push(Segment::Text(source[offset..start].into()));
+            }
+            let end = source[start..].find("}}")
+                .map(|end| start + end)
+                .ok_or(TemplateError::Unclosed { offset: start })?;
+            let name = source[start + 2..end].trim();
+            if !is_valid_name(name) {
+                return Err(TemplateError::InvalidName { name: name.into() });
+            }
+            segments.push(Segment::Variable(name.into()));
+            offset = end + 2;
+        }
+        if offset < source.len() {
+            segments.push(Segment::Text(source[offset..].into()));
+        }
+        Ok(Template { segments })
+    }
+
+    // The name of every variable used by the template
+    pub fn variables(&self) -> Vec<&str> {
+        self.segments.iter().filter_map(|segment| match *segment {
+            Segment::Variable(ref name) => Some(&**name),
+            Segment::Text(_) => None
+        }).collect()
+    }
+
+    pub fn render(&self, variables: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+        let mut result = String::new();
+        for segment in &self.segments {
+            match *segment {
+                Segment::Text(ref text) => result.push_str(text),
+                Segment::Variable(ref name) => match variables.get(name) {
+                    Some(value) => result.push_str(value),
+                    None => return Err(TemplateError::UnknownVariable { name: name.clone() })
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Template::parse(s)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> BTreeMap<String, String> {
+        vec![
+            ("recipient_name".to_string(), "Ada".to_string()),
+            ("sender".to_string(), "Acme".to_string()),
+        ].into_iter().collect()
+    }
+
+    #[test]
+    fn render_variables() {
+        let template: Template = "Hello {{recipient_name}}, {{ sender }} says hi.".parse().unwrap();
+        assert_eq!(template.render(&variables()).unwrap(), "Hello Ada, Acme says hi.");
+        assert_eq!(template.variables(), vec!["recipient_name", "sender"]);
+        let template: Template = "No variables } here".parse().unwrap();
+        assert_eq!(template.render(&variables()).unwrap(), "No variables } here");
+    }
+
+    #[test]
+    fn template_errors() {
+        assert_eq!(Template::parse("Hello {{name"), Err(TemplateError::Unclosed { offset: 6 }));
+        assert_eq!(Template::parse("Hello {{ }}"), Err(TemplateError::InvalidName { name: "".into() }));
+        assert_eq!(Template::parse("{{first name}}"), Err(TemplateError::InvalidName { name: "first name".into() }));
+        assert_eq!(
+            Template::parse("{{link}}").unwrap().render(&variables()),
+            Err(TemplateError::UnknownVariable { name: "link".into() })
+        );
+    }
+}
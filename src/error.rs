@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::fmt;
+
+use database::interface::DatabaseError;
+
+// Stable, machine-readable codes attached to every error returned
+// from the API. Clients should match on these rather than on the
+// human-readable message, which may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NotFound,
+    InvalidInput,
+    Conflict,
+    Forbidden,
+    PoolTimeout,
+    SerializationConflict,
+    CorruptData,
+    MigrationFailed,
+    DatabaseError,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::InvalidInput => "INVALID_INPUT",
+            ErrorCode::Conflict => "CONFLICT",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::PoolTimeout => "POOL_TIMEOUT",
+            ErrorCode::SerializationConflict => "SERIALIZATION_CONFLICT",
+            ErrorCode::CorruptData => "CORRUPT_DATA",
+            ErrorCode::MigrationFailed => "MIGRATION_FAILED",
+            ErrorCode::DatabaseError => "DATABASE_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// An error which can be reported back to an API client.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    // Path to the input field which caused the error, if any,
+    // eg. `data.addressHistory[0].address.structured.postalCode`
+    pub field: Option<String>,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            field: None
+        }
+    }
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+    pub fn invalid_input<S: Into<String>>(message: S) -> Self {
+        Self::new(ErrorCode::InvalidInput, message)
+    }
+    pub fn conflict<S: Into<String>>(message: S) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+    pub fn forbidden<S: Into<String>>(message: S) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+    // Prefix the field path with the field containing it, so that
+    // errors can be attributed as they propagate out of nested inputs.
+    pub fn at<S: Into<String>>(mut self, field: S) -> Self {
+        let mut path = field.into();
+        if let Some(inner) = self.field.take() {
+            if !inner.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(&inner);
+        }
+        self.field = Some(path);
+        self
+    }
+}
+
+// The code always comes first, followed by the field if there is one,
+// so that clients can extract them from the GraphQL error message.
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.code)?;
+        if let Some(ref field) = self.field {
+            write!(f, "{}: ", field)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ApiError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(e: DatabaseError) -> Self {
+        ApiError::new(e.code(), e.to_string())
+    }
+}
+
+// Allow API errors to be returned from GraphQL resolvers
+impl From<ApiError> for String {
+    fn from(e: ApiError) -> Self {
+        e.to_string()
+    }
+}
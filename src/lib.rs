@@ -1,5 +1,7 @@
 // Iron web framework and middleware
 extern crate iron;
+extern crate hyper;
+extern crate hyper_rustls;
 extern crate mount;
 extern crate logger;
 
@@ -14,6 +16,7 @@ extern crate r2d2_diesel;
 // Serde serialization framework
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate serde;
 
@@ -23,32 +26,60 @@ extern crate juniper;
 extern crate juniper_iron;
 
 // Misc. libraries
+#[macro_use]
+extern crate log;
 extern crate uuid;
+extern crate chrono;
+extern crate regex;
+extern crate hmac;
+extern crate sha2;
+#[macro_use]
+extern crate lazy_static;
 extern crate dotenv;
 extern crate pretty_env_logger;
 
 // Our modules
 #[macro_use]
 mod macros;
+mod error;
 mod api;
 pub mod schema;
+mod address_history;
+mod contact;
+mod satisfaction;
 mod routes;
 mod database;
+mod outbox;
+mod webhooks;
+mod dispatch;
+
+use std::sync::Arc;
 
 use iron::prelude::*;
 use logger::Logger;
 
 use database::middleware::DatabaseWrapper;
+use dispatch::middleware::DispatcherWrapper;
 
-pub use database::interface::Database;
+pub use database::interface::{Database, DatabaseError, DatabaseResult};
+pub use error::{ApiError, ErrorCode};
 pub use database::postgres;
+pub use database::memory;
+pub use dispatch::{Dispatcher, Message, Transport, TransportError, COMMUNICATION_QUEUED};
+pub use dispatch::{smtp, http_sms, file_sink, registry, template};
+pub use outbox::{Outbox, OutboxHandler, OutboxMessage, Relay, RelayHandle};
+pub use webhooks::{Webhook, WebhookDelivery, WebhookEvent, WebhookHandler, WEBHOOK_EVENT};
+// Receivers can use this to check the `X-Checkout-Signature` header
+pub use webhooks::sign as sign_webhook;
 
 // Inject dependencies and return an application
-pub fn create_app<D: Database>(
-    db: D
+pub fn create_app<D: Database, T: Into<Arc<Dispatcher>>>(
+    db: D,
+    dispatcher: T
 ) -> Chain {
     let mut chain = Chain::new(routes::get());
     chain.link(Logger::new(None));
     chain.link_before(DatabaseWrapper::new(db));
+    chain.link_before(DispatcherWrapper::new(dispatcher));
     chain
 }
@@ -9,11 +9,15 @@ extern crate checkout;
 
 // Imports
 use std::{thread, env};
+use std::sync::Arc;
 use std::time::Duration;
 
 use iron::prelude::*;
 
-use checkout::create_app;
+use checkout::{create_app, Dispatcher, Relay, WebhookHandler, COMMUNICATION_QUEUED, WEBHOOK_EVENT};
+use checkout::smtp::SmtpTransport;
+use checkout::http_sms::HttpSmsTransport;
+use checkout::registry::TemplateRegistry;
 use checkout::postgres::PgDatabase;
 use checkout::Database;
 
@@ -29,9 +33,37 @@ pub fn postgres_database() -> PgDatabase {
         .expect(&format!("Error connecting to {}", database_url))
 }
 
+// Configure whichever transports have been provided
+pub fn dispatcher() -> Dispatcher {
+    let mut dispatcher = Dispatcher::new();
+
+    if let Ok(address) = env::var("SMTP_ADDRESS") {
+        let sender = env::var("SMTP_SENDER")
+            .expect("SMTP_SENDER must be set when SMTP_ADDRESS is");
+        dispatcher = dispatcher.email_transport(SmtpTransport::new(address, sender));
+    }
+    if let Ok(url) = env::var("SMS_GATEWAY_URL") {
+        let mut transport = HttpSmsTransport::new(url);
+        if let Ok(token) = env::var("SMS_GATEWAY_TOKEN") {
+            transport = transport.token(token);
+        }
+        dispatcher = dispatcher.sms_transport(transport);
+    }
+    if let Ok(dir) = env::var("TEMPLATE_DIR") {
+        let templates = TemplateRegistry::load(&dir)
+            .unwrap_or_else(|e| panic!("Failed to load templates: {}", e));
+        dispatcher = dispatcher.templates(templates);
+    }
+    if let Ok(sender) = env::var("DEFAULT_SENDER") {
+        dispatcher = dispatcher.default_sender(sender);
+    }
+    dispatcher
+}
+
 // Create database middleware
 fn migrate_database() {
-    postgres_database().migrate();
+    postgres_database().migrate()
+        .expect("Failed to migrate database");
     println!("Up-to-date!");
 }
 
@@ -48,11 +80,22 @@ fn main() {
         }
     }
 
+    let db = Arc::new(postgres_database());
+    let dispatcher = Arc::new(dispatcher());
+
+    // Deliver side effects recorded in the outbox
+    let relay = Relay::new(db.clone())
+        .handler(COMMUNICATION_QUEUED, dispatcher.clone())
+        .handler(WEBHOOK_EVENT, WebhookHandler::new())
+        .spawn();
+
     let listener = Iron::new(create_app(
-        postgres_database()
+        db,
+        dispatcher
     )).http("0.0.0.0:3000").unwrap();
 
     println!("Server started on 0.0.0.0:3000");
 
     drop(listener);
+    relay.stop();
 }
@@ -0,0 +1,374 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{self, DateTime, Utc};
+use serde_json;
+use uuid::Uuid;
+
+use schema::outbox;
+use database::interface::{Database, DatabaseResult};
+
+// Side effects of a basket update, such as sending a notification,
+// are recorded in an outbox in the same transaction as the update.
+// A relay then delivers them in the background, so that they are
+// neither lost if the process dies, nor performed if the update is
+// rolled back. Delivery is at-least-once, so handlers must tolerate
+// receiving the same message more than once: the idempotency key
+// identifies duplicates.
+
+// A message waiting to be written as part of a basket update
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    pub topic: String,
+    pub payload: serde_json::Value,
+    // Defaults to a key derived from the basket version
+    pub idempotency_key: Option<String>,
+}
+
+// The messages produced by a single basket update
+#[derive(Debug, Default, Clone)]
+pub struct Outbox {
+    messages: Vec<PendingMessage>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<S: Into<String>>(&mut self, topic: S, payload: serde_json::Value) {
+        self.messages.push(PendingMessage {
+            topic: topic.into(),
+            payload,
+            idempotency_key: None,
+        });
+    }
+
+    // Messages with the same key as an existing message are dropped
+    pub fn push_with_key<S: Into<String>, K: Into<String>>(&mut self, topic: S, payload: serde_json::Value, key: K) {
+        self.messages.push(PendingMessage {
+            topic: topic.into(),
+            payload,
+            idempotency_key: Some(key.into()),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Prepare the messages to be stored, once the new version of the
+    // basket is known. The default keys are stable if the update is
+    // retried, since a retry produces the same version number.
+    pub fn into_rows(self, basket_id: Uuid, version: i32) -> Vec<NewOutboxMessage> {
+        let now = Utc::now();
+        self.messages.into_iter().enumerate().map(|(index, message)| {
+            NewOutboxMessage {
+                idempotency_key: message.idempotency_key
+                    .unwrap_or_else(|| format!("{}:{}:{}", basket_id, version, index)),
+                topic: message.topic,
+                basket_id,
+                payload: message.payload,
+                created_at: now,
+                next_attempt_at: now,
+            }
+        }).collect()
+    }
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name="outbox"]
+pub struct NewOutboxMessage {
+    pub idempotency_key: String,
+    pub topic: String,
+    pub basket_id: Uuid,
+    pub payload: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub idempotency_key: String,
+    pub topic: String,
+    pub basket_id: Uuid,
+    pub payload: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    // Number of failed delivery attempts so far
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    // Set when the relay gives up on the message
+    pub abandoned_at: Option<DateTime<Utc>>,
+}
+
+impl OutboxMessage {
+    pub fn is_pending(&self) -> bool {
+        self.delivered_at.is_none() && self.abandoned_at.is_none()
+    }
+}
+
+
+// Handler must:
+// - be thread-safe (Send + Sync)
+// - live as long as required ('static)
+pub trait OutboxHandler: Send + Sync + 'static + Debug {
+    // Returning an error causes the message to be retried later
+    fn handle(&self, db: &Database, message: &OutboxMessage) -> Result<(), String>;
+}
+
+impl<H: OutboxHandler + ?Sized> OutboxHandler for Arc<H> {
+    fn handle(&self, db: &Database, message: &OutboxMessage) -> Result<(), String> {
+        (**self).handle(db, message)
+    }
+}
+
+
+fn to_duration(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::max_value())
+}
+
+// Delivers outbox messages to the handler registered for their topic
+#[derive(Debug)]
+pub struct Relay {
+    db: Arc<Database>,
+    handlers: HashMap<String, Box<OutboxHandler>>,
+    batch_size: usize,
+    poll_interval: Duration,
+    // How long a claimed message is hidden from other relays. If the
+    // relay dies while handling it, it is delivered again afterwards.
+    lease: Duration,
+    min_delay: Duration,
+    max_delay: Duration,
+    max_attempts: i32,
+}
+
+impl Relay {
+    pub fn new(db: Arc<Database>) -> Self {
+        Relay {
+            db,
+            handlers: HashMap::new(),
+            batch_size: 20,
+            poll_interval: Duration::from_secs(1),
+            lease: Duration::from_secs(300),
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3600),
+            max_attempts: 20,
+        }
+    }
+
+    pub fn handler<S: Into<String>, H: OutboxHandler>(mut self, topic: S, handler: H) -> Self {
+        self.handlers.insert(topic.into(), Box::new(handler));
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    // The delay after the first failure, which doubles with each
+    // subsequent failure up to `max_delay`.
+    pub fn retry_delay(mut self, min_delay: Duration, max_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    // How long to wait before the next attempt, given the number
+    // of attempts which have failed.
+    pub fn backoff(&self, attempts: i32) -> Duration {
+        let shift = cmp::min(cmp::max(attempts, 1) - 1, 31) as u32;
+        self.min_delay.checked_mul(1 << shift)
+            .map(|delay| cmp::min(delay, self.max_delay))
+            .unwrap_or(self.max_delay)
+    }
+
+    // Deliver a batch of messages which are due, returning how many
+    // were claimed.
+    pub fn run_once(&self) -> DatabaseResult<usize> {
+        let messages = self.db.claim_outbox_messages(self.batch_size, to_duration(self.lease))?;
+        for message in &messages {
+            let result = match self.handlers.get(&message.topic) {
+                Some(handler) => handler.handle(&*self.db, message),
+                None => Err(format!("No handler for topic `{}`", message.topic))
+            };
+            match result {
+                Ok(()) => self.db.complete_outbox_message(message.id)?,
+                Err(error) => {
+                    let attempts = message.attempts + 1;
+                    let next_attempt_at = if attempts >= self.max_attempts {
+                        warn!("Abandoning outbox message {} after {} attempts: {}", message.idempotency_key, attempts, error);
+                        None
+                    } else {
+                        Some(Utc::now() + to_duration(self.backoff(attempts)))
+                    };
+                    self.db.retry_outbox_message(message.id, &error, next_attempt_at)?;
+                }
+            }
+        }
+        Ok(messages.len())
+    }
+
+    // Run the relay on a background thread until it is stopped
+    pub fn spawn(self) -> RelayHandle {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stopped.load(Ordering::SeqCst) {
+                match self.run_once() {
+                    // Keep going while there is a backlog
+                    Ok(count) if count >= self.batch_size => continue,
+                    Ok(_) => {},
+                    Err(e) => error!("Failed to relay outbox messages: {}", e),
+                }
+                thread::sleep(self.poll_interval);
+            }
+        });
+        RelayHandle { stopped, thread }
+    }
+}
+
+pub struct RelayHandle {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl RelayHandle {
+    // Wait for the current batch to finish, and stop the relay
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use database::memory::MemoryDatabase;
+    use schema::Basket;
+
+    #[derive(Debug, Default)]
+    struct FlakyHandler {
+        // Fail this many times before succeeding
+        failures: Mutex<u32>,
+        delivered: Mutex<Vec<String>>,
+    }
+
+    impl OutboxHandler for FlakyHandler {
+        fn handle(&self, _db: &Database, message: &OutboxMessage) -> Result<(), String> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err("Unavailable".into());
+            }
+            self.delivered.lock().unwrap().push(message.idempotency_key.clone());
+            Ok(())
+        }
+    }
+
+    fn enqueue(db: &MemoryDatabase, basket_id: Uuid, topic: &str) {
+        (db as &Database).update_basket_with_outbox(basket_id, &mut |_: &mut Basket, outbox: &mut Outbox| {
+            outbox.push(topic, json!({ "basket_id": basket_id }));
+            Ok::<(), String>(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn backoff() {
+        let relay = Relay::new(Arc::new(MemoryDatabase::new()))
+            .retry_delay(Duration::from_secs(2), Duration::from_secs(60));
+        assert_eq!(relay.backoff(1), Duration::from_secs(2));
+        assert_eq!(relay.backoff(2), Duration::from_secs(4));
+        assert_eq!(relay.backoff(5), Duration::from_secs(32));
+        assert_eq!(relay.backoff(6), Duration::from_secs(60));
+        assert_eq!(relay.backoff(1000), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn retry_until_delivered() {
+        let db = Arc::new(MemoryDatabase::new());
+        let handler = Arc::new(FlakyHandler { failures: Mutex::new(2), ..Default::default() });
+        let relay = Relay::new(db.clone())
+            .handler("test", handler.clone())
+            .retry_delay(Duration::from_secs(0), Duration::from_secs(0));
+        let basket_id = Uuid::new_v4();
+        enqueue(&db, basket_id, "test");
+
+        assert_eq!(relay.run_once().unwrap(), 1);
+        assert_eq!(relay.run_once().unwrap(), 1);
+        let message = db.outbox_messages().pop().unwrap();
+        assert_eq!(message.attempts, 2);
+        assert_eq!(message.last_error.as_ref().map(|s| &**s), Some("Unavailable"));
+        assert!(message.is_pending());
+
+        assert_eq!(relay.run_once().unwrap(), 1);
+        assert_eq!(relay.run_once().unwrap(), 0);
+        assert!(db.outbox_messages()[0].delivered_at.is_some());
+        assert_eq!(*handler.delivered.lock().unwrap(), vec![format!("{}:1:0", basket_id)]);
+    }
+
+    #[test]
+    fn abandon_after_max_attempts() {
+        let db = Arc::new(MemoryDatabase::new());
+        let relay = Relay::new(db.clone())
+            .retry_delay(Duration::from_secs(0), Duration::from_secs(0))
+            .max_attempts(2);
+        enqueue(&db, Uuid::new_v4(), "unknown");
+
+        assert_eq!(relay.run_once().unwrap(), 1);
+        assert_eq!(relay.run_once().unwrap(), 1);
+        assert_eq!(relay.run_once().unwrap(), 0);
+        let message = db.outbox_messages().pop().unwrap();
+        assert!(message.abandoned_at.is_some());
+        assert_eq!(message.last_error.as_ref().map(|s| &**s), Some("No handler for topic `unknown`"));
+    }
+
+    #[test]
+    fn rolled_back_updates_are_not_delivered() {
+        let db = MemoryDatabase::new();
+        let result = (&db as &Database).update_basket_with_outbox(Uuid::new_v4(), &mut |_: &mut Basket, outbox: &mut Outbox| {
+            outbox.push("test", json!({}));
+            Err("Invalid".to_string())
+        });
+        assert!(result.is_err());
+        assert!(db.outbox_messages().is_empty());
+    }
+
+    #[test]
+    fn duplicate_keys_are_dropped() {
+        let db = MemoryDatabase::new();
+        let basket_id = Uuid::new_v4();
+        for _ in 0..2 {
+            (&db as &Database).update_basket_with_outbox(basket_id, &mut |_: &mut Basket, outbox: &mut Outbox| {
+                outbox.push_with_key("test", json!({}), "once");
+                Ok::<(), String>(())
+            }).unwrap();
+        }
+        assert_eq!(db.outbox_messages().len(), 1);
+    }
+}
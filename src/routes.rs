@@ -1,5 +1,7 @@
 #![allow(non_snake_case)]
 
+use std::collections::BTreeSet;
+
 use iron::prelude::*;
 use mount::Mount;
 use uuid::Uuid;
@@ -10,16 +12,78 @@ use serde::{Serialize, Deserialize, self};
 
 use api::*;
 use schema::*;
+use error::ApiError;
+use contact::{self, PhoneNumber};
+use satisfaction::{StepEvaluation, StepStatus};
+use address_history::{self, AddressFinding, AddressFindingKind, AddressHistoryAnalysis};
 use database::middleware::{DatabaseRequestExt, DatabaseWrapper};
+use dispatch::{self, Message};
+use dispatch::registry;
+use outbox::Outbox;
+use webhooks::{self, Webhook, WebhookDelivery, WebhookEvent};
+use dispatch::middleware::{DispatcherRequestExt, DispatcherWrapper};
 
 struct Query;
 struct Mutation;
 
-impl Context for DatabaseWrapper {}
+// This service does not authenticate callers itself. It must be deployed
+// behind a gateway which does, and which sets this header to the tenant
+// the caller is acting for, discarding any value sent by the caller.
+const TENANT_HEADER: &str = "X-Tenant-Id";
+
+// Everything available to GraphQL resolvers
+struct RequestContext {
+    db: DatabaseWrapper,
+    dispatcher: DispatcherWrapper,
+    // The tenant the caller is acting for, if any
+    tenant_id: Option<String>,
+}
+
+impl Context for RequestContext {}
+
+impl RequestContext {
+    // Only a tenant may manage its webhooks or take ownership of baskets,
+    // since either of those would let the caller receive its events.
+    fn authorize_tenant(&self, tenant_id: &str) -> Result<(), ApiError> {
+        match self.tenant_id {
+            Some(ref caller) if caller == tenant_id => Ok(()),
+            Some(_) => Err(ApiError::forbidden("Not authorized to act for this tenant")),
+            None => Err(ApiError::forbidden(format!("The {} header is required", TENANT_HEADER)))
+        }
+    }
+
+    // A basket which belongs to a tenant can only be read or changed by
+    // that tenant. Baskets without a tenant are open to every caller.
+    fn authorize_basket(&self, basket: &Basket) -> Result<(), ApiError> {
+        match basket.contents.0.tenant_id {
+            Some(ref tenant_id) => self.authorize_tenant(tenant_id),
+            None => Ok(())
+        }
+    }
+}
 
 struct EmailRecipient(Recipient);
 struct SmsRecipient(Recipient);
 
+impl SmsRecipient {
+    fn phone_number(&self) -> &str {
+        if let ContactMethod::Sms { ref phone_number } = self.0.contact_method {
+            phone_number
+        } else {
+            unreachable!()
+        }
+    }
+}
+
+struct FullNameStep(CollectionStep);
+struct DobStep(CollectionStep);
+struct AddressHistoryStep(CollectionStep);
+struct NationalityStep(CollectionStep);
+struct DocumentStep(CollectionStep);
+struct CompanyNameStep(CollectionStep);
+struct RegistrationNumberStep(CollectionStep);
+struct JurisdictionStep(CollectionStep);
+
 // Convert between GraphQL "value" and JSON "value"
 fn into_scalar<T: Serialize>(v: T) -> Result<Value, serde_json::Error> {
     use serde_json::Value as JsonValue;
@@ -63,6 +127,52 @@ fn from_scalar<T: for<'a> Deserialize<'a>>(v: InputValue) -> Result<T, serde_jso
     inner(v).map_err(|_| serde::de::Error::custom("Non-JSON input value")).and_then(serde_json::from_value)
 }
 
+// Reject a change if the basket has been modified since the version
+// the caller expected, if any.
+fn check_version(basket: &Basket, expected_version: Option<i32>) -> Result<(), ApiError> {
+    if let Some(expected_version) = expected_version {
+        if basket.version != expected_version {
+            return Err(ApiError::conflict(format!(
+                "Basket is at version {}, but version {} was expected",
+                basket.version, expected_version
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Apply a change to the contents of a basket, converting any failure
+// into a GraphQL error. If an expected version is given, the change is
+// rejected when the basket has been modified since that version.
+fn mutate_basket<F>(context: &RequestContext, basket_id: Uuid, expected_version: Option<i32>, mut f: F) -> FieldResult<Basket>
+    where F: FnMut(&mut BasketContentsV2) -> Result<(), ApiError>
+{
+    mutate_basket_with_outbox(context, basket_id, expected_version, |contents, _| f(contents))
+}
+
+// As `mutate_basket`, but the change may also record side effects
+// in the outbox, which are only delivered if the change succeeds.
+fn mutate_basket_with_outbox<F>(context: &RequestContext, basket_id: Uuid, expected_version: Option<i32>, mut f: F) -> FieldResult<Basket>
+    where F: FnMut(&mut BasketContentsV2, &mut Outbox) -> Result<(), ApiError>
+{
+    context.db.update_basket_with_outbox(basket_id, &mut |basket: &mut Basket, outbox: &mut Outbox| {
+        context.authorize_basket(basket)?;
+        check_version(basket, expected_version)?;
+        // Baskets stored before references were validated may already be
+        // inconsistent, so only reject problems introduced by this change.
+        let existing_problems = basket.contents.0.validate();
+        f(&mut basket.contents.0, outbox)?;
+        let new_problems: Vec<_> = basket.contents.0.validate().into_iter()
+            .filter(|problem| !existing_problems.contains(problem))
+            .map(|problem| problem.to_string())
+            .collect();
+        if !new_problems.is_empty() {
+            return Err(ApiError::invalid_input(new_problems.join("; ")));
+        }
+        Ok(())
+    }).map_err(Into::into)
+}
+
 graphql_enum!(TaskType {
     TaskType::IndividualVerifyIdentity => "INDIVIDUAL_VERIFY_IDENTITY",
     TaskType::IndividualVerifyAddress => "INDIVIDUAL_VERIFY_ADDRESS",
@@ -100,7 +210,865 @@ graphql_enum!(CheckType {
     CheckType::CompanyPepsAndSanctionsScreen => "COMPANY_PEPS_AND_SANCTIONS_SCREEN",
 });
 
-graphql_object!(Check: DatabaseWrapper |&self| {
+graphql_enum!(EntityType {
+    EntityType::Individual => "INDIVIDUAL",
+    EntityType::Company => "COMPANY",
+});
+
+graphql_enum!(CommunicationKind {
+    CommunicationKind::Invite => "INVITE",
+    CommunicationKind::Reminder => "REMINDER",
+    CommunicationKind::CompletionNotice => "COMPLETION_NOTICE",
+});
+
+graphql_enum!(CommunicationStatus {
+    CommunicationStatus::Draft => "DRAFT",
+    CommunicationStatus::Queued => "QUEUED",
+    CommunicationStatus::Sending => "SENDING",
+    CommunicationStatus::Sent => "SENT",
+    CommunicationStatus::Failed => "FAILED",
+    CommunicationStatus::Cancelled => "CANCELLED",
+});
+
+graphql_enum!(WebhookEvent {
+    WebhookEvent::BasketCreated => "BASKET_CREATED",
+    WebhookEvent::ProfileAdded => "PROFILE_ADDED",
+    WebhookEvent::RecipientSelected => "RECIPIENT_SELECTED",
+    WebhookEvent::CheckoutSubmitted => "CHECKOUT_SUBMITTED",
+    WebhookEvent::CommunicationSent => "COMMUNICATION_SENT",
+});
+
+graphql_enum!(OfficerRole {
+    OfficerRole::Director => "DIRECTOR",
+    OfficerRole::Secretary => "SECRETARY",
+    OfficerRole::Partner => "PARTNER",
+    OfficerRole::Other => "OTHER",
+});
+
+graphql_enum!(DatePrecision {
+    DatePrecision::Year => "YEAR",
+    DatePrecision::YearMonth => "YEAR_MONTH",
+    DatePrecision::YearMonthDay => "YEAR_MONTH_DAY",
+});
+
+graphql_enum!(DocumentCategory {
+    DocumentCategory::ProofOfIdentity => "PROOF_OF_IDENTITY",
+    DocumentCategory::ProofOfAddress => "PROOF_OF_ADDRESS",
+    DocumentCategory::Supporting => "SUPPORTING",
+    DocumentCategory::CompanyFiling => "COMPANY_FILING",
+    DocumentCategory::DataSummary => "DATA_SUMMARY",
+});
+
+graphql_enum!(DocumentType {
+    DocumentType::Passport => "PASSPORT",
+    DocumentType::DrivingLicence => "DRIVING_LICENCE",
+    DocumentType::StateId => "STATE_ID",
+    DocumentType::BirthCertificate => "BIRTH_CERTIFICATE",
+    DocumentType::BankStatement => "BANK_STATEMENT",
+    DocumentType::FaceImage => "FACE_IMAGE",
+    DocumentType::Unknown => "UNKNOWN",
+    DocumentType::CompanyAccounts => "COMPANY_ACCOUNTS",
+    DocumentType::CompanyChangeOfAddress => "COMPANY_CHANGE_OF_ADDRESS",
+    DocumentType::AnnualReturn => "ANNUAL_RETURN",
+    DocumentType::ConfirmationStatement => "CONFIRMATION_STATEMENT",
+    DocumentType::StatementOfCaptital => "STATEMENT_OF_CAPTITAL",
+    DocumentType::ChangeOfName => "CHANGE_OF_NAME",
+    DocumentType::Incorporation => "INCORPORATION",
+    DocumentType::Liquidation => "LIQUIDATION",
+    DocumentType::Miscellaneous => "MISCELLANEOUS",
+    DocumentType::Mortgage => "MORTGAGE",
+    DocumentType::ChangeOfOfficers => "CHANGE_OF_OFFICERS",
+    DocumentType::Resolution => "RESOLUTION",
+    DocumentType::CreditReport => "CREDIT_REPORT",
+    DocumentType::CreditCheck => "CREDIT_CHECK",
+    DocumentType::RegisterReport => "REGISTER_REPORT",
+    DocumentType::RegisterCheck => "REGISTER_CHECK",
+    DocumentType::DataSummary => "DATA_SUMMARY",
+});
+
+graphql_enum!(CollectionStepKind {
+    CollectionStepKind::FullName => "FULL_NAME",
+    CollectionStepKind::Dob => "DOB",
+    CollectionStepKind::AddressHistory => "ADDRESS_HISTORY",
+    CollectionStepKind::Nationality => "NATIONALITY",
+    CollectionStepKind::Document => "DOCUMENT",
+    CollectionStepKind::CompanyName => "COMPANY_NAME",
+    CollectionStepKind::RegistrationNumber => "REGISTRATION_NUMBER",
+    CollectionStepKind::Jurisdiction => "JURISDICTION",
+});
+
+graphql_input_object!(
+    description: "A piece of information to collect from the end user"
+
+    struct CollectionStepInput {
+        kind: CollectionStepKind as "The kind of step",
+        precision: Option<DatePrecision> as "Required for DOB steps",
+        months: Option<i32> as "Required for ADDRESS_HISTORY steps",
+        documentId: Option<Uuid> as "Identifies a DOCUMENT step, generated if omitted when adding a step",
+        category: Option<DocumentCategory> as "Required for DOCUMENT steps",
+        allowedTypes: Option<Vec<DocumentType>> as "Required for DOCUMENT steps",
+    }
+);
+
+impl CollectionStepInput {
+    fn to_collection_step(&self) -> Result<CollectionStep, ApiError> {
+        fn required<T>(value: Option<T>, name: &str) -> Result<T, ApiError> {
+            value.ok_or_else(|| ApiError::invalid_input(format!("`{}` is required for this kind of step", name)))
+        }
+
+        Ok(match self.kind {
+            CollectionStepKind::FullName => CollectionStep::FullName {},
+            CollectionStepKind::Dob => CollectionStep::Dob {
+                precision: required(self.precision, "precision")?
+            },
+            CollectionStepKind::AddressHistory => {
+                let months = required(self.months, "months")?;
+                if months < 0 {
+                    return Err(ApiError::invalid_input("`months` must not be negative"));
+                }
+                CollectionStep::AddressHistory { months: months as u32 }
+            },
+            CollectionStepKind::Nationality => CollectionStep::Nationality {},
+            CollectionStepKind::Document => {
+                let allowed_types: BTreeSet<_> = required(self.allowedTypes.as_ref(), "allowedTypes")?
+                    .iter().cloned().collect();
+                if allowed_types.is_empty() {
+                    return Err(ApiError::invalid_input("`allowedTypes` must not be empty"));
+                }
+                CollectionStep::Document {
+                    id: self.documentId.unwrap_or_else(Uuid::new_v4),
+                    category: required(self.category, "category")?,
+                    allowed_types
+                }
+            },
+            CollectionStepKind::CompanyName => CollectionStep::CompanyName {},
+            CollectionStepKind::RegistrationNumber => CollectionStep::RegistrationNumber {},
+            CollectionStepKind::Jurisdiction => CollectionStep::Jurisdiction {},
+        })
+    }
+}
+
+graphql_scalar!(PartialDate {
+    description: "A date of the form YYYY, YYYY-MM or YYYY-MM-DD"
+
+    resolve(&self) -> Value {
+        Value::String(self.to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<PartialDate> {
+        v.as_string_value().and_then(|s| s.parse().ok())
+    }
+});
+
+graphql_scalar!(ApproxDate {
+    description: "An approximate date of the form YYYY, YYYY-MM or YYYY-MM-DD"
+
+    resolve(&self) -> Value {
+        Value::String(self.to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<ApproxDate> {
+        v.as_string_value().and_then(|s| s.parse().ok())
+    }
+});
+
+graphql_input_object!(
+    description: "The name of an individual"
+
+    struct FullNameInput {
+        title: Option<String>,
+        givenNames: Option<Vec<String>>,
+        familyName: Option<String>,
+        altFamilyNames: Option<Vec<String>>,
+    }
+);
+
+graphql_input_object!(
+    description: "Personal details of an individual"
+
+    struct PersonalDetailsInput {
+        name: Option<FullNameInput>,
+        dob: Option<PartialDate>,
+        nationality: Option<String>,
+    }
+);
+
+graphql_input_object!(
+    description: "An address split into its components"
+
+    struct StructuredAddressInput {
+        country: String,
+        stateProvince: Option<String>,
+        county: Option<String>,
+        postalCode: String,
+        locality: Option<String>,
+        postalTown: Option<String>,
+        route: Option<String>,
+        streetNumber: Option<String>,
+        premise: Option<String>,
+        subpremise: Option<String>,
+    }
+);
+
+graphql_input_object!(
+    description: "An address as free text"
+
+    struct FreeformAddressInput {
+        country: String,
+        text: String,
+    }
+);
+
+graphql_input_object!(
+    description: "An address, exactly one field must be given"
+
+    struct AddressInput {
+        structured: Option<StructuredAddressInput>,
+        freeform: Option<FreeformAddressInput>,
+    }
+);
+
+graphql_input_object!(
+    description: "An address and the period during which it was occupied"
+
+    struct DatedAddressInput {
+        startDate: Option<ApproxDate>,
+        endDate: Option<ApproxDate>,
+        address: AddressInput,
+    }
+);
+
+graphql_input_object!(
+    description: "A document provided by an individual"
+
+    struct DocumentInput {
+        id: Option<Uuid>,
+        category: DocumentCategory,
+        documentType: DocumentType,
+    }
+);
+
+graphql_input_object!(
+    description: "Information collected about an individual"
+
+    struct IndividualDataInput {
+        personalDetails: Option<PersonalDetailsInput>,
+        addressHistory: Option<Vec<DatedAddressInput>>,
+        documents: Option<Vec<DocumentInput>>,
+    }
+);
+
+graphql_input_object!(
+    description: "A current or former officer of a company"
+
+    struct OfficerInput {
+        name: String,
+        role: OfficerRole,
+        appointedOn: Option<PartialDate>,
+        resignedOn: Option<PartialDate>,
+    }
+);
+
+graphql_input_object!(
+    description: "A person or company with a share in a company"
+
+    struct BeneficialOwnerInput {
+        name: String,
+        entityType: EntityType,
+        ownershipPercentage: f64,
+    }
+);
+
+graphql_input_object!(
+    description: "A document filed by a company"
+
+    struct FilingInput {
+        id: Option<Uuid>,
+        documentType: DocumentType,
+        filedOn: Option<PartialDate>,
+        description: Option<String>,
+    }
+);
+
+graphql_input_object!(
+    description: "Information collected about a company"
+
+    struct CompanyDataInput {
+        legalName: Option<String>,
+        registrationNumber: Option<String>,
+        jurisdiction: Option<String>,
+        registeredAddress: Option<AddressInput>,
+        incorporationDate: Option<PartialDate>,
+        officers: Option<Vec<OfficerInput>>,
+        beneficialOwners: Option<Vec<BeneficialOwnerInput>>,
+        filings: Option<Vec<FilingInput>>,
+    }
+);
+
+impl FullNameInput {
+    fn to_full_name(&self) -> FullName {
+        FullName {
+            title: self.title.clone(),
+            given_names: self.givenNames.clone(),
+            family_name: self.familyName.clone(),
+            alt_family_names: self.altFamilyNames.clone(),
+        }
+    }
+}
+
+impl PersonalDetailsInput {
+    fn to_personal_details(&self) -> PersonalDetails {
+        PersonalDetails {
+            name: self.name.as_ref().map(FullNameInput::to_full_name).unwrap_or_default(),
+            dob: self.dob,
+            nationality: self.nationality.clone(),
+        }
+    }
+}
+
+impl AddressInput {
+    fn to_address(&self) -> Result<Address, ApiError> {
+        let mut address = match (&self.structured, &self.freeform) {
+            (&Some(ref a), &None) => Ok(Address::StructuredAddress(StructuredAddress {
+                country: a.country.clone(),
+                state_province: a.stateProvince.clone(),
+                county: a.county.clone(),
+                postal_code: a.postalCode.clone(),
+                locality: a.locality.clone(),
+                postal_town: a.postalTown.clone(),
+                route: a.route.clone(),
+                street_number: a.streetNumber.clone(),
+                premise: a.premise.clone(),
+                subpremise: a.subpremise.clone(),
+            })),
+            (&None, &Some(ref a)) => Ok(Address::FreeformAddress(FreeformAddress {
+                country: a.country.clone(),
+                text: a.text.clone(),
+            })),
+            _ => Err(ApiError::invalid_input("Exactly one of `structured` or `freeform` must be given for an address"))
+        }?;
+        address.normalize()?;
+        Ok(address)
+    }
+}
+
+impl DatedAddressInput {
+    fn to_dated_address(&self) -> Result<DatedAddress, ApiError> {
+        Ok(DatedAddress {
+            start_date: self.startDate,
+            end_date: self.endDate,
+            address: self.address.to_address().map_err(|e| e.at("address"))?,
+        })
+    }
+}
+
+impl DocumentInput {
+    fn to_document(&self) -> Document {
+        Document {
+            id: self.id,
+            category: self.category,
+            document_type: self.documentType,
+        }
+    }
+}
+
+impl IndividualDataInput {
+    fn to_individual_data(&self) -> Result<IndividualData, ApiError> {
+        let personal_details = self.personalDetails.as_ref()
+            .map(PersonalDetailsInput::to_personal_details)
+            .unwrap_or_default();
+        let address_history = match self.addressHistory {
+            Some(ref history) => {
+                let mut history = history.iter().enumerate()
+                    .map(|(i, address)| address.to_dated_address().map_err(|e| e.at(format!("[{}]", i))))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.at("addressHistory"))?;
+                // Errors above use the submitted order, but findings refer
+                // to the stored order, which is most recent first.
+                address_history::sort(&mut history);
+                Some(history)
+            },
+            None => None
+        };
+        Ok(IndividualData {
+            personal_details,
+            address_history,
+            documents: self.documents.as_ref()
+                .map(|documents| documents.iter().map(DocumentInput::to_document).collect()),
+        })
+    }
+}
+
+graphql_input_object!(
+    description: "How to contact a recipient, exactly one field must be given"
+
+    struct ContactMethodInput {
+        email: Option<String>,
+        phoneNumber: Option<String>,
+    }
+);
+
+graphql_input_object!(
+    description: "The fields of a communication which the front-end may set"
+
+    struct PublicArgsInput {
+        from: Option<String>,
+        bcc: Option<Vec<String>>,
+        locale: Option<String>,
+    }
+);
+
+impl PublicArgsInput {
+    fn to_public_args(&self) -> Result<PublicArgs, ApiError> {
+        let bcc = match self.bcc {
+            Some(ref bcc) => bcc.iter().enumerate()
+                .map(|(i, address)| contact::normalize_email(address).map_err(|e| e.at(format!("[{}]", i))))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.at("bcc"))?,
+            None => Vec::new()
+        };
+        let locale = match self.locale {
+            Some(ref locale) => Some(registry::normalize_locale(locale)
+                .ok_or_else(|| ApiError::invalid_input(format!("Invalid locale: {}", locale)).at("locale"))?),
+            None => None
+        };
+        Ok(PublicArgs {
+            from: self.from.clone(),
+            bcc,
+            locale,
+        })
+    }
+}
+
+impl ContactMethodInput {
+    fn to_contact_method(&self) -> Result<ContactMethod, ApiError> {
+        match (&self.email, &self.phoneNumber) {
+            (&Some(ref address), &None) => Ok(ContactMethod::Email {
+                address: contact::normalize_email(address).map_err(|e| e.at("email"))?
+            }),
+            (&None, &Some(ref phone_number)) => Ok(ContactMethod::Sms {
+                phone_number: contact::normalize_phone_number(phone_number).map_err(|e| e.at("phoneNumber"))?
+            }),
+            _ => Err(ApiError::invalid_input("Exactly one of `email` or `phoneNumber` must be given for a contact method"))
+        }
+    }
+}
+
+impl OfficerInput {
+    fn to_officer(&self) -> Officer {
+        Officer {
+            name: self.name.clone(),
+            role: self.role,
+            appointed_on: self.appointedOn,
+            resigned_on: self.resignedOn,
+        }
+    }
+}
+
+impl BeneficialOwnerInput {
+    fn to_beneficial_owner(&self) -> BeneficialOwner {
+        BeneficialOwner {
+            name: self.name.clone(),
+            entity_type: self.entityType,
+            ownership_percentage: self.ownershipPercentage,
+        }
+    }
+}
+
+impl FilingInput {
+    fn to_filing(&self) -> Filing {
+        Filing {
+            id: self.id,
+            document_type: self.documentType,
+            filed_on: self.filedOn,
+            description: self.description.clone(),
+        }
+    }
+}
+
+impl CompanyDataInput {
+    fn to_company_data(&self) -> Result<CompanyData, ApiError> {
+        let registered_address = match self.registeredAddress {
+            Some(ref address) => Some(address.to_address().map_err(|e| e.at("registeredAddress"))?),
+            None => None
+        };
+        let mut data = CompanyData {
+            legal_name: self.legalName.clone(),
+            registration_number: self.registrationNumber.clone(),
+            jurisdiction: self.jurisdiction.clone(),
+            registered_address,
+            incorporation_date: self.incorporationDate,
+            officers: self.officers.as_ref()
+                .map(|officers| officers.iter().map(OfficerInput::to_officer).collect()),
+            beneficial_owners: self.beneficialOwners.as_ref()
+                .map(|owners| owners.iter().map(BeneficialOwnerInput::to_beneficial_owner).collect()),
+            filings: self.filings.as_ref()
+                .map(|filings| filings.iter().map(FilingInput::to_filing).collect()),
+        };
+        data.normalize()?;
+        Ok(data)
+    }
+}
+
+graphql_object!(FullNameStep: RequestContext |&self| {
+    description: "Collect the full name of an individual"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(DobStep: RequestContext |&self| {
+    description: "Collect the date of birth of an individual"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+    field precision(&executor) -> DatePrecision {
+        if let CollectionStep::Dob { precision } = self.0 {
+            precision
+        } else {
+            unreachable!()
+        }
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(AddressHistoryStep: RequestContext |&self| {
+    description: "Collect the address history of an individual"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+    field months(&executor) -> i32 {
+        if let CollectionStep::AddressHistory { months } = self.0 {
+            months as i32
+        } else {
+            unreachable!()
+        }
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(NationalityStep: RequestContext |&self| {
+    description: "Collect the nationality of an individual"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(DocumentStep: RequestContext |&self| {
+    description: "Collect a document"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+    field id(&executor) -> Uuid {
+        if let CollectionStep::Document { id, .. } = self.0 {
+            id
+        } else {
+            unreachable!()
+        }
+    }
+    field category(&executor) -> DocumentCategory {
+        if let CollectionStep::Document { category, .. } = self.0 {
+            category
+        } else {
+            unreachable!()
+        }
+    }
+    field allowedTypes(&executor) -> Vec<DocumentType> {
+        if let CollectionStep::Document { ref allowed_types, .. } = self.0 {
+            allowed_types.iter().cloned().collect()
+        } else {
+            unreachable!()
+        }
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(CompanyNameStep: RequestContext |&self| {
+    description: "Collect the legal name of a company"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(RegistrationNumberStep: RequestContext |&self| {
+    description: "Collect the registration number of a company"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_object!(JurisdictionStep: RequestContext |&self| {
+    description: "Collect the jurisdiction a company is registered in"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.0.kind()
+    }
+
+    interfaces: [CollectionStep]
+});
+
+graphql_interface!(CollectionStep: RequestContext |&self| {
+    description: "A piece of information to collect from the end user"
+
+    field kind(&executor) -> CollectionStepKind {
+        self.kind()
+    }
+    instance_resolvers: |_| {
+        FullNameStep => if let CollectionStep::FullName {} = *self { Some(FullNameStep(self.clone())) } else { None },
+        DobStep => if let CollectionStep::Dob {..} = *self { Some(DobStep(self.clone())) } else { None },
+        AddressHistoryStep => if let CollectionStep::AddressHistory {..} = *self { Some(AddressHistoryStep(self.clone())) } else { None },
+        NationalityStep => if let CollectionStep::Nationality {} = *self { Some(NationalityStep(self.clone())) } else { None },
+        DocumentStep => if let CollectionStep::Document {..} = *self { Some(DocumentStep(self.clone())) } else { None },
+        CompanyNameStep => if let CollectionStep::CompanyName {} = *self { Some(CompanyNameStep(self.clone())) } else { None },
+        RegistrationNumberStep => if let CollectionStep::RegistrationNumber {} = *self { Some(RegistrationNumberStep(self.clone())) } else { None },
+        JurisdictionStep => if let CollectionStep::Jurisdiction {} = *self { Some(JurisdictionStep(self.clone())) } else { None },
+    }
+});
+
+graphql_enum!(StepStatus {
+    StepStatus::Satisfied => "SATISFIED",
+    StepStatus::Partial => "PARTIAL",
+    StepStatus::Missing => "MISSING",
+});
+
+graphql_object!(StepEvaluation: RequestContext |&self| {
+    description: "How much of a collection step has been satisfied"
+
+    field step(&executor) -> &CollectionStep {
+        &self.step
+    }
+    field status(&executor) -> StepStatus {
+        self.status
+    }
+    field findings(&executor) -> &[AddressFinding] {
+        &self.findings
+    }
+});
+
+graphql_enum!(AddressFindingKind {
+    AddressFindingKind::MissingCurrentAddress => "MISSING_CURRENT_ADDRESS",
+    AddressFindingKind::UnknownStartDate => "UNKNOWN_START_DATE",
+    AddressFindingKind::EndBeforeStart => "END_BEFORE_START",
+    AddressFindingKind::Gap => "GAP",
+    AddressFindingKind::Overlap => "OVERLAP",
+});
+
+graphql_object!(AddressFinding: RequestContext |&self| {
+    description: "A problem with an address history"
+
+    field kind(&executor) -> AddressFindingKind {
+        self.kind()
+    }
+    field addressIndex(&executor) -> Option<i32> {
+        match *self {
+            AddressFinding::UnknownStartDate { index } |
+            AddressFinding::EndBeforeStart { index } => Some(index as i32),
+            AddressFinding::Overlap { first, .. } => Some(first as i32),
+            _ => None
+        }
+    }
+    field otherAddressIndex(&executor) -> Option<i32> {
+        match *self {
+            AddressFinding::Overlap { second, .. } => Some(second as i32),
+            _ => None
+        }
+    }
+    field from(&executor) -> Option<ApproxDate> {
+        match *self {
+            AddressFinding::Gap { from, .. } => Some(from),
+            _ => None
+        }
+    }
+    field to(&executor) -> Option<ApproxDate> {
+        match *self {
+            AddressFinding::Gap { to, .. } => Some(to),
+            _ => None
+        }
+    }
+    field months(&executor) -> Option<i32> {
+        match *self {
+            AddressFinding::Gap { months, .. } |
+            AddressFinding::Overlap { months, .. } => Some(months as i32),
+            _ => None
+        }
+    }
+});
+
+graphql_object!(AddressHistoryAnalysis: RequestContext |&self| {
+    description: "An analysis of the submitted address history"
+
+    field findings(&executor) -> &[AddressFinding] {
+        &self.findings
+    }
+    field monthsCovered(&executor) -> i32 {
+        self.months_covered as i32
+    }
+    field continuousMonths(&executor) -> i32 {
+        self.continuous_months as i32
+    }
+});
+
+graphql_object!(StructuredAddress: RequestContext |&self| {
+    description: "An address split into its components"
+
+    field country(&executor) -> &str {
+        &self.country
+    }
+    field stateProvince(&executor) -> &Option<String> {
+        &self.state_province
+    }
+    field county(&executor) -> &Option<String> {
+        &self.county
+    }
+    field postalCode(&executor) -> &str {
+        &self.postal_code
+    }
+    field locality(&executor) -> &Option<String> {
+        &self.locality
+    }
+    field postalTown(&executor) -> &Option<String> {
+        &self.postal_town
+    }
+    field route(&executor) -> &Option<String> {
+        &self.route
+    }
+    field streetNumber(&executor) -> &Option<String> {
+        &self.street_number
+    }
+    field premise(&executor) -> &Option<String> {
+        &self.premise
+    }
+    field subpremise(&executor) -> &Option<String> {
+        &self.subpremise
+    }
+
+    interfaces: [Address]
+});
+
+graphql_object!(FreeformAddress: RequestContext |&self| {
+    description: "An address as free text"
+
+    field country(&executor) -> &str {
+        &self.country
+    }
+    field text(&executor) -> &str {
+        &self.text
+    }
+
+    interfaces: [Address]
+});
+
+graphql_interface!(Address: RequestContext |&self| {
+    description: "A postal address"
+
+    field country(&executor) -> &str {
+        match *self {
+            Address::StructuredAddress(ref address) => &address.country,
+            Address::FreeformAddress(ref address) => &address.country,
+        }
+    }
+    instance_resolvers: |_| {
+        StructuredAddress => if let Address::StructuredAddress(ref address) = *self { Some(address.clone()) } else { None },
+        FreeformAddress => if let Address::FreeformAddress(ref address) = *self { Some(address.clone()) } else { None },
+    }
+});
+
+graphql_object!(Officer: RequestContext |&self| {
+    description: "A current or former officer of a company"
+
+    field name(&executor) -> &str {
+        &self.name
+    }
+    field role(&executor) -> OfficerRole {
+        self.role
+    }
+    field appointedOn(&executor) -> Option<PartialDate> {
+        self.appointed_on
+    }
+    field resignedOn(&executor) -> Option<PartialDate> {
+        self.resigned_on
+    }
+});
+
+graphql_object!(BeneficialOwner: RequestContext |&self| {
+    description: "A person or company with a share in a company"
+
+    field name(&executor) -> &str {
+        &self.name
+    }
+    field entityType(&executor) -> EntityType {
+        self.entity_type
+    }
+    field ownershipPercentage(&executor) -> f64 {
+        self.ownership_percentage
+    }
+});
+
+graphql_object!(Filing: RequestContext |&self| {
+    description: "A document filed by a company"
+
+    field id(&executor) -> Option<Uuid> {
+        self.id
+    }
+    field documentType(&executor) -> DocumentType {
+        self.document_type
+    }
+    field filedOn(&executor) -> Option<PartialDate> {
+        self.filed_on
+    }
+    field description(&executor) -> &Option<String> {
+        &self.description
+    }
+});
+
+graphql_object!(CompanyData: RequestContext |&self| {
+    description: "Information collected about a company"
+
+    field legalName(&executor) -> &Option<String> {
+        &self.legal_name
+    }
+    field registrationNumber(&executor) -> &Option<String> {
+        &self.registration_number
+    }
+    field jurisdiction(&executor) -> &Option<String> {
+        &self.jurisdiction
+    }
+    field registeredAddress(&executor) -> &Option<Address> {
+        &self.registered_address
+    }
+    field incorporationDate(&executor) -> Option<PartialDate> {
+        self.incorporation_date
+    }
+    field officers(&executor) -> &[Officer] {
+        self.officers.as_ref().map_or(&[][..], |officers| &officers[..])
+    }
+    field beneficialOwners(&executor) -> &[BeneficialOwner] {
+        self.beneficial_owners.as_ref().map_or(&[][..], |owners| &owners[..])
+    }
+    field filings(&executor) -> &[Filing] {
+        self.filings.as_ref().map_or(&[][..], |filings| &filings[..])
+    }
+});
+
+graphql_object!(Check: RequestContext |&self| {
     description: "A single check to run"
 
     field id(&executor) -> Uuid {
@@ -114,12 +1082,15 @@ graphql_object!(Check: DatabaseWrapper |&self| {
     }
 });
 
-graphql_object!(Profile: DatabaseWrapper |&self| {
+graphql_object!(Profile: RequestContext |&self| {
     description: "A profile to check"
 
     field id(&executor) -> Uuid {
         self.id
     }
+    field entityType(&executor) -> EntityType {
+        self.entity_type
+    }
     field possibleRecipients(&executor) -> &[Uuid] {
         &self.possible_recipients
     }
@@ -130,12 +1101,30 @@ graphql_object!(Profile: DatabaseWrapper |&self| {
         self.selected_recipient
     }
     field needsInformation(&executor) -> bool {
-        !self.calculated_collection_steps.is_empty()
+        self.needs_information()
+    }
+    field outstandingSteps(&executor) -> Vec<StepEvaluation> {
+        self.outstanding_steps()
+    }
+    field addressHistory(&executor) -> Option<AddressHistoryAnalysis> {
+        self.address_history_analysis()
+    }
+    field companyData(&executor) -> Option<&CompanyData> {
+        match self.entity_data {
+            Some(EntityData::CompanyData(ref data)) => Some(data),
+            _ => None
+        }
+    }
+    field collectionSteps(&executor) -> &[CollectionStep] {
+        &self.calculated_collection_steps
+    }
+    field extraCollectionSteps(&executor) -> &[CollectionStep] {
+        &self.extra_collection_steps
     }
 });
 
 graphql_scalar!(PrivateArgs {
-    description: "An opaque JSON object passed through to templating service"
+    description: "An opaque JSON object set by the server, which controls how the message is rendered"
 
     resolve(&self) -> Value {
         into_scalar(self).expect("Failed to serialize private args")
@@ -146,7 +1135,7 @@ graphql_scalar!(PrivateArgs {
     }
 });
 
-graphql_object!(PublicArgs: DatabaseWrapper |&self| {
+graphql_object!(PublicArgs: RequestContext |&self| {
     description: "The set of fields customisable by the front-end"
 
     field from(&executor) -> &Option<String> {
@@ -155,11 +1144,46 @@ graphql_object!(PublicArgs: DatabaseWrapper |&self| {
     field bcc(&executor) -> &Vec<String> {
         &self.bcc
     }
+    field locale(&executor) -> &Option<String> {
+        &self.locale
+    }
+});
+
+graphql_object!(Message: RequestContext |&self| {
+    description: "A communication rendered from its template"
+
+    field to(&executor) -> &str {
+        &self.to
+    }
+    field from(&executor) -> &Option<String> {
+        &self.from
+    }
+    field bcc(&executor) -> &Vec<String> {
+        &self.bcc
+    }
+    field subject(&executor) -> &str {
+        &self.subject
+    }
+    field body(&executor) -> &str {
+        &self.body
+    }
+    field locale(&executor) -> &str {
+        &self.locale
+    }
 });
 
-graphql_object!(Communication: DatabaseWrapper |&self| {
+graphql_object!(Communication: RequestContext |&self| {
     description: "A communication with the end user"
 
+    field id(&executor) -> Uuid {
+        self.id
+    }
+    field kind(&executor) -> CommunicationKind {
+        self.kind
+    }
+    field status(&executor) -> CommunicationStatus {
+        self.status
+    }
     field recipient(&executor) -> Uuid {
         self.recipient
     }
@@ -169,9 +1193,26 @@ graphql_object!(Communication: DatabaseWrapper |&self| {
     field privateArgs(&executor) -> &PrivateArgs {
         &self.private_args
     }
+    field deliveryAttempts(&executor) -> &[DeliveryAttempt] {
+        &self.delivery_attempts
+    }
+});
+
+graphql_object!(DeliveryAttempt: RequestContext |&self| {
+    description: "An attempt to send a communication"
+
+    field attemptedAt(&executor) -> String {
+        self.attempted_at.to_rfc3339()
+    }
+    field transport(&executor) -> &Option<String> {
+        &self.transport
+    }
+    field error(&executor) -> &Option<String> {
+        &self.error
+    }
 });
 
-graphql_object!(EmailRecipient: DatabaseWrapper |&self| {
+graphql_object!(EmailRecipient: RequestContext |&self| {
     description: "A recipient to be contacted via email"
 
     field id(&executor) -> Uuid {
@@ -191,7 +1232,7 @@ graphql_object!(EmailRecipient: DatabaseWrapper |&self| {
     interfaces: [Recipient]
 });
 
-graphql_object!(SmsRecipient: DatabaseWrapper |&self| {
+graphql_object!(SmsRecipient: RequestContext |&self| {
     description: "A recipient to be contacted via SMS"
 
     field id(&executor) -> Uuid {
@@ -200,18 +1241,19 @@ graphql_object!(SmsRecipient: DatabaseWrapper |&self| {
     field name(&executor) -> &str {
         &self.0.name
     }
-    field phoneNumber(&executor) -> &str {
-        if let ContactMethod::Sms { ref phone_number } = self.0.contact_method {
-            phone_number
-        } else {
-            unreachable!()
-        }
+    field phoneNumber(&executor) -> String {
+        // Numbers stored before they were validated are returned as-is
+        let phone_number = self.phone_number();
+        PhoneNumber::parse(phone_number).map(|number| number.to_e164()).unwrap_or_else(|_| phone_number.into())
+    }
+    field nationalFormat(&executor) -> Option<String> {
+        PhoneNumber::parse(self.phone_number()).ok().map(|number| number.national_format())
     }
 
     interfaces: [Recipient]
 });
 
-graphql_interface!(Recipient: DatabaseWrapper |&self| {
+graphql_interface!(Recipient: RequestContext |&self| {
     description: "A recipient of a communication"
 
     field id(&executor) -> Uuid {
@@ -226,12 +1268,15 @@ graphql_interface!(Recipient: DatabaseWrapper |&self| {
     }
 });
 
-graphql_object!(Basket: DatabaseWrapper |&self| {
+graphql_object!(Basket: RequestContext |&self| {
     description: "A single basket"
 
     field id(&executor) -> Uuid {
         self.id
     }
+    field version(&executor) -> i32 {
+        self.version
+    }
     field profilesToCheck(&executor) -> &[Profile] {
         &self.contents.0.profiles_to_check
     }
@@ -241,30 +1286,398 @@ graphql_object!(Basket: DatabaseWrapper |&self| {
     field recipients(&executor) -> &[Recipient] {
         &self.contents.0.recipients
     }
+    field tenantId(&executor) -> &Option<String> {
+        &self.contents.0.tenant_id
+    }
+    field submittedAt(&executor) -> Option<String> {
+        self.contents.0.submitted_at.map(|submitted_at| submitted_at.to_rfc3339())
+    }
+});
+
+graphql_object!(Webhook: RequestContext |&self| {
+    description: "An endpoint which is sent a tenant's basket events"
+
+    field id(&executor) -> Uuid {
+        self.id
+    }
+    field tenantId(&executor) -> &str {
+        &self.tenant_id
+    }
+    field url(&executor) -> &str {
+        &self.url
+    }
+    field events(&executor) -> Vec<WebhookEvent> {
+        self.subscribed_events()
+    }
+    field createdAt(&executor) -> String {
+        self.created_at.to_rfc3339()
+    }
+    // The most recent delivery attempts, newest first
+    field deliveries(&executor, limit: Option<i32>) -> FieldResult<Vec<WebhookDelivery>> {
+        let limit = limit.unwrap_or(50);
+        if limit < 1 || limit > 500 {
+            return Err(ApiError::invalid_input("Limit must be between 1 and 500").at("limit").into());
+        }
+        executor.context().db.get_webhook_deliveries(self.id, limit as usize)
+            .map_err(|e| ApiError::from(e).into())
+    }
 });
 
-graphql_object!(Query: DatabaseWrapper |&self| {
+graphql_object!(WebhookDelivery: RequestContext |&self| {
+    description: "An attempt to deliver an event to a webhook"
+
+    field id(&executor) -> Uuid {
+        self.id
+    }
+    field eventId(&executor) -> &str {
+        &self.event_id
+    }
+    field event(&executor) -> Option<WebhookEvent> {
+        self.event.parse().ok()
+    }
+    field attemptedAt(&executor) -> String {
+        self.attempted_at.to_rfc3339()
+    }
+    field responseStatus(&executor) -> Option<i32> {
+        self.response_status
+    }
+    field error(&executor) -> &Option<String> {
+        &self.error
+    }
+    field succeeded(&executor) -> bool {
+        self.error.is_none()
+    }
+});
+
+graphql_object!(Query: RequestContext |&self| {
     description: "The root query object of the schema"
     
-    field basket(&executor, id: Uuid) -> FieldResult<Basket> {
-        executor.context().update_basket(id, &mut |_| Ok(()))
+    field basket(&executor, id: Uuid) -> FieldResult<Option<Basket>> {
+        let context = executor.context();
+        let basket = context.db.get_basket(id)
+            .map_err(ApiError::from)?;
+        if let Some(ref basket) = basket {
+            context.authorize_basket(basket)?;
+        }
+        Ok(basket)
+    }
+
+    field availableTasks(&executor, entityType: EntityType) -> Vec<TaskType> {
+        TaskType::all_for(entityType)
+    }
+
+    field availableChecks(&executor, task: TaskType) -> &[CheckType] {
+        task.available_checks()
+    }
+
+    field normalizeAddress(&executor, address: AddressInput) -> FieldResult<StructuredAddress> {
+        let address = address.to_address().map_err(|e| e.at("address"))?;
+        Ok(match address {
+            Address::StructuredAddress(address) => address,
+            Address::FreeformAddress(address) => address.to_structured()
+        })
+    }
+
+    field webhooks(&executor, tenantId: String) -> FieldResult<Vec<Webhook>> {
+        let tenant_id = webhooks::normalize_tenant_id(&tenantId).map_err(|e| e.at("tenantId"))?;
+        let context = executor.context();
+        context.authorize_tenant(&tenant_id)?;
+        context.db.get_webhooks(&tenant_id)
+            .map_err(|e| ApiError::from(e).into())
+    }
+
+    // Render a communication without sending it
+    field previewCommunication(&executor, basketId: Uuid, communicationId: Uuid) -> FieldResult<Message> {
+        let context = executor.context();
+        let basket = context.db.get_basket(basketId)
+            .map_err(ApiError::from)?
+            .ok_or_else(|| ApiError::not_found("Basket ID not found"))?;
+        context.authorize_basket(&basket)?;
+        context.dispatcher.preview(&basket, communicationId)
+            .map_err(Into::into)
     }
 });
 
-graphql_object!(Mutation: DatabaseWrapper |&self| {
+graphql_object!(Mutation: RequestContext |&self| {
     description: "The root mutation object of the schema"
 
-    field setRecipientOnProfile(&executor, basketId: Uuid, profileId: Uuid, recipientId: Option<Uuid>) -> FieldResult<Basket> {
-        executor.context().update_basket(basketId, &mut |basket| {
-            let profile = basket.contents.0.find_profile_mut(profileId).ok_or("Profile ID not found")?;
-            profile.selected_recipient = recipientId;
+    field createBasket(&executor, tenantId: Option<String>) -> FieldResult<Basket> {
+        let tenant_id = match tenantId {
+            Some(ref tenant_id) => Some(webhooks::normalize_tenant_id(tenant_id).map_err(|e| e.at("tenantId"))?),
+            None => None
+        };
+        if let Some(ref tenant_id) = tenant_id {
+            executor.context().authorize_tenant(tenant_id)?;
+        }
+        let basket_id = Uuid::new_v4();
+        mutate_basket_with_outbox(executor.context(), basket_id, None, |contents, outbox| {
+            contents.tenant_id = tenant_id.clone();
+            webhooks::emit(outbox, contents, basket_id, WebhookEvent::BasketCreated, json!({}));
+            Ok(())
+        })
+    }
+
+    // Baskets can also be created implicitly by any other mutation, in
+    // which case they do not belong to a tenant until this is called.
+    // Unlike other mutations, this never creates the basket.
+    field setBasketTenant(&executor, basketId: Uuid, tenantId: String, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let tenant_id = webhooks::normalize_tenant_id(&tenantId).map_err(|e| e.at("tenantId"))?;
+        let context = executor.context();
+        context.authorize_tenant(&tenant_id)?;
+        context.db.update_basket(basketId, &mut |basket: &mut Basket| {
+            if basket.version == 0 {
+                return Err(ApiError::not_found("Basket ID not found"));
+            }
+            context.authorize_basket(basket)?;
+            check_version(basket, expectedVersion)?;
+            basket.contents.0.set_tenant(&tenant_id)
+        }).map_err(Into::into)
+    }
+
+    field addProfile(&executor, basketId: Uuid, profileId: Uuid, entityType: Option<EntityType>, possibleRecipients: Option<Vec<Uuid>>, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket_with_outbox(executor.context(), basketId, expectedVersion, |contents, outbox| {
+            contents.add_profile(Profile {
+                id: profileId,
+                entity_type: entityType.unwrap_or_default(),
+                possible_recipients: possibleRecipients.clone().unwrap_or_default(),
+                ..Default::default()
+            })?;
+            webhooks::emit(outbox, contents, basketId, WebhookEvent::ProfileAdded, json!({
+                "profile_id": profileId,
+            }));
+            Ok(())
+        })
+    }
+
+    field removeProfile(&executor, basketId: Uuid, profileId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.remove_profile(profileId).map(|_| ())
+        })
+    }
+
+    field moveProfile(&executor, basketId: Uuid, profileId: Uuid, index: i32, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        if index < 0 {
+            return Err(ApiError::invalid_input("Profile index out of range").into());
+        }
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.move_profile(profileId, index as usize)
+        })
+    }
+
+    field addCheck(&executor, basketId: Uuid, profileId: Uuid, task: TaskType, check: CheckType, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let check_id = Uuid::new_v4();
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.add_check(Check {
+                id: check_id,
+                task,
+                check,
+            })
+        })
+    }
+
+    field removeCheck(&executor, basketId: Uuid, profileId: Uuid, checkId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.remove_check(checkId).map(|_| ())
+        })
+    }
+
+    field addExtraCollectionStep(&executor, basketId: Uuid, profileId: Uuid, step: CollectionStepInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let step = step.to_collection_step()?;
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.add_extra_collection_step(step.clone());
+            Ok(())
+        })
+    }
+
+    field removeExtraCollectionStep(&executor, basketId: Uuid, profileId: Uuid, step: CollectionStepInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.remove_extra_collection_step(step.kind, step.documentId)
+        })
+    }
+
+    field submitProfileData(&executor, basketId: Uuid, profileId: Uuid, data: IndividualDataInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let data = data.to_individual_data().map_err(|e| e.at("data"))?;
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.set_entity_data(EntityData::IndividualData(data.clone()))
+        })
+    }
+
+    field submitCompanyData(&executor, basketId: Uuid, profileId: Uuid, data: CompanyDataInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let data = data.to_company_data().map_err(|e| e.at("data"))?;
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let profile = contents.find_profile_mut(profileId)
+                .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+            profile.set_entity_data(EntityData::CompanyData(data.clone()))
+        })
+    }
+
+    field setRecipientOnProfile(&executor, basketId: Uuid, profileId: Uuid, recipientId: Option<Uuid>, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket_with_outbox(executor.context(), basketId, expectedVersion, |contents, outbox| {
+            let changed = {
+                let profile = contents.find_profile_mut(profileId)
+                    .ok_or_else(|| ApiError::not_found("Profile ID not found"))?;
+                let changed = profile.selected_recipient != recipientId;
+                profile.selected_recipient = recipientId;
+                changed
+            };
+            if let (true, Some(recipient_id)) = (changed, recipientId) {
+                webhooks::emit(outbox, contents, basketId, WebhookEvent::RecipientSelected, json!({
+                    "profile_id": profileId,
+                    "recipient_id": recipient_id,
+                }));
+            }
             Ok(())
         })
     }
+
+    field submitCheckout(&executor, basketId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket_with_outbox(executor.context(), basketId, expectedVersion, |contents, outbox| {
+            contents.submit()?;
+            let profile_ids: Vec<_> = contents.profiles_to_check.iter().map(|p| p.id).collect();
+            webhooks::emit(outbox, contents, basketId, WebhookEvent::CheckoutSubmitted, json!({
+                "profile_ids": profile_ids,
+            }));
+            Ok(())
+        })
+    }
+
+    field addRecipient(&executor, basketId: Uuid, recipientId: Uuid, name: String, contactMethod: ContactMethodInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let contact_method = contactMethod.to_contact_method().map_err(|e| e.at("contactMethod"))?;
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.add_recipient(Recipient {
+                id: recipientId,
+                name: name.clone(),
+                contact_method: contact_method.clone(),
+            })
+        })
+    }
+
+    field updateRecipient(&executor, basketId: Uuid, recipientId: Uuid, name: Option<String>, contactMethod: Option<ContactMethodInput>, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let contact_method = match contactMethod {
+            Some(ref method) => Some(method.to_contact_method().map_err(|e| e.at("contactMethod"))?),
+            None => None
+        };
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            let recipient = contents.find_recipient_mut(recipientId)
+                .ok_or_else(|| ApiError::not_found("Recipient ID not found"))?;
+            if let Some(ref name) = name {
+                recipient.name = name.clone();
+            }
+            if let Some(ref contact_method) = contact_method {
+                recipient.contact_method = contact_method.clone();
+            }
+            Ok(())
+        })
+    }
+
+    // There is deliberately no way for the front-end to set private args
+    field createCommunication(&executor, basketId: Uuid, recipientId: Uuid, kind: CommunicationKind, publicArgs: Option<PublicArgsInput>, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let public_args = match publicArgs {
+            Some(ref args) => args.to_public_args().map_err(|e| e.at("publicArgs"))?,
+            None => Default::default()
+        };
+        let communication_id = Uuid::new_v4();
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.communications.push(Communication {
+                id: communication_id,
+                kind,
+                recipient: recipientId,
+                public_args: public_args.clone(),
+                ..Default::default()
+            });
+            Ok(())
+        })
+    }
+
+    field updateCommunicationPublicArgs(&executor, basketId: Uuid, communicationId: Uuid, publicArgs: PublicArgsInput, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        let public_args = publicArgs.to_public_args().map_err(|e| e.at("publicArgs"))?;
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.find_communication_mut(communicationId)?.set_public_args(public_args.clone())
+        })
+    }
+
+    // Queue the communication to be sent in the background
+    field queueCommunication(&executor, basketId: Uuid, communicationId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket_with_outbox(executor.context(), basketId, expectedVersion, |contents, outbox| {
+            contents.find_communication_mut(communicationId)?.set_status(CommunicationStatus::Queued)?;
+            dispatch::enqueue(outbox, basketId, communicationId);
+            Ok(())
+        })
+    }
+
+    // Equivalent to `queueCommunication`, except that a communication which
+    // is already queued is left as it is. Sending always happens in the
+    // background, so that only the outbox relay dispatches communications.
+    field sendCommunication(&executor, basketId: Uuid, communicationId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket_with_outbox(executor.context(), basketId, expectedVersion, |contents, outbox| {
+            let communication = contents.find_communication_mut(communicationId)?;
+            if communication.status != CommunicationStatus::Queued {
+                communication.set_status(CommunicationStatus::Queued)?;
+                dispatch::enqueue(outbox, basketId, communicationId);
+            }
+            Ok(())
+        })
+    }
+
+    field cancelCommunication(&executor, basketId: Uuid, communicationId: Uuid, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.find_communication_mut(communicationId)?.set_status(CommunicationStatus::Cancelled)
+        })
+    }
+
+    // Register a webhook for some of a tenant's basket events. Each payload
+    // is signed using the secret, which is never returned by the API.
+    field createWebhook(&executor, tenantId: String, url: String, secret: String, events: Vec<WebhookEvent>) -> FieldResult<Webhook> {
+        let webhook = Webhook::new(&tenantId, &url, &secret, &events)?;
+        let context = executor.context();
+        context.authorize_tenant(&webhook.tenant_id)?;
+        context.db.insert_webhook(&webhook)
+            .map_err(ApiError::from)?;
+        Ok(webhook)
+    }
+
+    // Webhooks belonging to other tenants are reported as not found
+    field deleteWebhook(&executor, id: Uuid) -> FieldResult<Webhook> {
+        let context = executor.context();
+        let tenant_id = context.tenant_id.clone().unwrap_or_default();
+        context.authorize_tenant(&tenant_id)?;
+        let webhooks = context.db.get_webhooks(&tenant_id)
+            .map_err(ApiError::from)?;
+        if !webhooks.iter().any(|webhook| webhook.id == id) {
+            return Err(ApiError::not_found("Webhook ID not found").into());
+        }
+        context.db.delete_webhook(id)
+            .map_err(ApiError::from)?
+            .ok_or_else(|| ApiError::not_found("Webhook ID not found").into())
+    }
+
+    field removeRecipient(&executor, basketId: Uuid, recipientId: Uuid, clearReferences: Option<bool>, expectedVersion: Option<i32>) -> FieldResult<Basket> {
+        mutate_basket(executor.context(), basketId, expectedVersion, |contents| {
+            contents.remove_recipient(recipientId, clearReferences.unwrap_or(false)).map(|_| ())
+        })
+    }
 });
 
-fn context_factory(req: &mut Request) -> DatabaseWrapper {
-    req.db()
+fn context_factory(req: &mut Request) -> RequestContext {
+    let tenant_id = req.headers.get_raw(TENANT_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+        .and_then(|value| webhooks::normalize_tenant_id(&value).ok());
+    RequestContext {
+        db: req.db(),
+        dispatcher: req.dispatcher(),
+        tenant_id,
+    }
 }
 
 pub fn get() -> Mount {
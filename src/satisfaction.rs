@@ -0,0 +1,267 @@
+use chrono::NaiveDate;
+
+use api::{CollectionStep, CompanyData, DatedAddress, DocumentCategory, EntityData, IndividualData};
+use address_history::{self, AddressFinding};
+
+
+// How much of the information requested by a collection step
+// has been provided.
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StepStatus {
+    Satisfied,
+    Partial,
+    Missing
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepEvaluation {
+    pub step: CollectionStep,
+    pub status: StepStatus,
+    // Problems with the address history, for address history steps
+    pub findings: Vec<AddressFinding>
+}
+
+
+fn address_history_status(history: &[DatedAddress], months: u32, today: NaiveDate) -> StepStatus {
+    if history.is_empty() {
+        return StepStatus::Missing;
+    }
+    let analysis = address_history::analyse(history, today);
+    if analysis.has_current_address() && analysis.continuous_months >= months {
+        StepStatus::Satisfied
+    } else {
+        StepStatus::Partial
+    }
+}
+
+// Determine how much of a collection step is satisfied by the
+// information collected about an individual.
+pub fn evaluate_individual_step(step: &CollectionStep, data: &IndividualData, today: NaiveDate) -> StepStatus {
+    let details = &data.personal_details;
+    match *step {
+        CollectionStep::FullName {} => {
+            let has_given_names = details.name.given_names.as_ref().map_or(false, |names| !names.is_empty());
+            let has_family_name = details.name.family_name.is_some();
+            match (has_given_names, has_family_name) {
+                (true, true) => StepStatus::Satisfied,
+                (false, false) => StepStatus::Missing,
+                _ => StepStatus::Partial
+            }
+        },
+        CollectionStep::Dob { precision } => match details.dob {
+            Some(dob) if dob.precision() >= precision => StepStatus::Satisfied,
+            Some(_) => StepStatus::Partial,
+            None => StepStatus::Missing
+        },
+        CollectionStep::AddressHistory { months } => {
+            let history = data.address_history.as_ref().map_or(&[][..], |history| &history[..]);
+            address_history_status(history, months, today)
+        },
+        CollectionStep::Nationality {} => if details.nationality.is_some() {
+            StepStatus::Satisfied
+        } else {
+            StepStatus::Missing
+        },
+        CollectionStep::Document { category, ref allowed_types, .. } => {
+            let documents = data.documents.as_ref().map_or(&[][..], |documents| &documents[..]);
+            let mut status = StepStatus::Missing;
+            for document in documents.iter().filter(|document| document.category == category) {
+                if allowed_types.contains(&document.document_type) {
+                    return StepStatus::Satisfied;
+                }
+                // A document was provided, but not of an acceptable type
+                status = StepStatus::Partial;
+            }
+            status
+        },
+        // Company information cannot be provided by an individual
+        CollectionStep::CompanyName {} |
+        CollectionStep::RegistrationNumber {} |
+        CollectionStep::Jurisdiction {} => StepStatus::Missing,
+    }
+}
+
+fn text_status(value: &Option<String>) -> StepStatus {
+    match *value {
+        Some(ref value) if !value.trim().is_empty() => StepStatus::Satisfied,
+        _ => StepStatus::Missing
+    }
+}
+
+// Determine how much of a collection step is satisfied by the
+// information collected about a company.
+pub fn evaluate_company_step(step: &CollectionStep, data: &CompanyData) -> StepStatus {
+    match *step {
+        CollectionStep::CompanyName {} => text_status(&data.legal_name),
+        CollectionStep::RegistrationNumber {} => text_status(&data.registration_number),
+        CollectionStep::Jurisdiction {} => text_status(&data.jurisdiction),
+        // Companies can only provide their filings as documents
+        CollectionStep::Document { category: DocumentCategory::CompanyFiling, ref allowed_types, .. } => {
+            let filings = data.filings.as_ref().map_or(&[][..], |filings| &filings[..]);
+            if filings.iter().any(|filing| allowed_types.contains(&filing.document_type)) {
+                StepStatus::Satisfied
+            } else if !filings.is_empty() {
+                StepStatus::Partial
+            } else {
+                StepStatus::Missing
+            }
+        },
+        // Personal information cannot be provided by a company
+        CollectionStep::Document { .. } |
+        CollectionStep::FullName {} |
+        CollectionStep::Dob { .. } |
+        CollectionStep::AddressHistory { .. } |
+        CollectionStep::Nationality {} => StepStatus::Missing,
+    }
+}
+
+pub fn evaluate_step(step: &CollectionStep, data: Option<&EntityData>, today: NaiveDate) -> StepStatus {
+    match data {
+        Some(&EntityData::IndividualData(ref data)) => evaluate_individual_step(step, data, today),
+        Some(&EntityData::CompanyData(ref data)) => evaluate_company_step(step, data),
+        None => StepStatus::Missing
+    }
+}
+
+fn step_findings(step: &CollectionStep, data: Option<&EntityData>, today: NaiveDate) -> Vec<AddressFinding> {
+    match (step, data) {
+        (&CollectionStep::AddressHistory { .. }, Some(&EntityData::IndividualData(ref data))) => {
+            match data.address_history {
+                Some(ref history) if !history.is_empty() => address_history::analyse(history, today).findings,
+                _ => Vec::new()
+            }
+        },
+        _ => Vec::new()
+    }
+}
+
+pub fn evaluate(steps: &[CollectionStep], data: Option<&EntityData>, today: NaiveDate) -> Vec<StepEvaluation> {
+    steps.iter().map(|step| StepEvaluation {
+        step: step.clone(),
+        status: evaluate_step(step, data, today),
+        findings: step_findings(step, data, today)
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use uuid::Uuid;
+    use api::{DatePrecision, Document, DocumentType, Filing};
+    use address_history::tests::{address, today};
+
+    fn status_of(step: CollectionStep, data: &IndividualData) -> StepStatus {
+        evaluate_individual_step(&step, data, today())
+    }
+
+    #[test]
+    fn full_name() {
+        let mut data = IndividualData::default();
+        assert_eq!(status_of(CollectionStep::FullName {}, &data), StepStatus::Missing);
+        data.personal_details.name.family_name = Some("Lovelace".into());
+        assert_eq!(status_of(CollectionStep::FullName {}, &data), StepStatus::Partial);
+        data.personal_details.name.given_names = Some(vec!["Ada".into()]);
+        assert_eq!(status_of(CollectionStep::FullName {}, &data), StepStatus::Satisfied);
+    }
+
+    #[test]
+    fn dob_precision() {
+        let step = CollectionStep::Dob { precision: DatePrecision::YearMonthDay };
+        let mut data = IndividualData::default();
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Missing);
+        data.personal_details.dob = Some("1815-12".parse().unwrap());
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Partial);
+        assert_eq!(status_of(CollectionStep::Dob { precision: DatePrecision::Year }, &data), StepStatus::Satisfied);
+        data.personal_details.dob = Some("1815-12-10".parse().unwrap());
+        assert_eq!(status_of(step, &data), StepStatus::Satisfied);
+    }
+
+    #[test]
+    fn address_history_coverage() {
+        let step = CollectionStep::AddressHistory { months: 36 };
+        let mut data = IndividualData::default();
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Missing);
+
+        // Only one year at the current address
+        data.address_history = Some(vec![address(Some("2016-10"), None)]);
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Partial);
+        assert_eq!(status_of(CollectionStep::AddressHistory { months: 0 }, &data), StepStatus::Satisfied);
+
+        // A gap between the previous and current address
+        data.address_history = Some(vec![
+            address(Some("2016-10"), None),
+            address(Some("2010-01"), Some("2016-08")),
+        ]);
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Partial);
+
+        // Contiguous history going back far enough
+        data.address_history = Some(vec![
+            address(Some("2016-10"), None),
+            address(Some("2010-01"), Some("2016-09")),
+        ]);
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Satisfied);
+
+        // No current address
+        data.address_history = Some(vec![address(Some("2010-01"), Some("2016-09"))]);
+        assert_eq!(status_of(step, &data), StepStatus::Partial);
+    }
+
+    #[test]
+    fn company_details() {
+        let mut data = CompanyData::default();
+        assert_eq!(evaluate_company_step(&CollectionStep::CompanyName {}, &data), StepStatus::Missing);
+        data.legal_name = Some("Acme Widgets Ltd".into());
+        data.jurisdiction = Some(" ".into());
+        assert_eq!(evaluate_company_step(&CollectionStep::CompanyName {}, &data), StepStatus::Satisfied);
+        assert_eq!(evaluate_company_step(&CollectionStep::Jurisdiction {}, &data), StepStatus::Missing);
+        assert_eq!(evaluate_company_step(&CollectionStep::FullName {}, &data), StepStatus::Missing);
+    }
+
+    #[test]
+    fn company_filings() {
+        let step = CollectionStep::Document {
+            id: Uuid::new_v4(),
+            category: DocumentCategory::CompanyFiling,
+            allowed_types: vec![DocumentType::ConfirmationStatement].into_iter().collect::<BTreeSet<_>>()
+        };
+        let filing = |document_type| Filing {
+            id: None,
+            document_type,
+            filed_on: None,
+            description: None
+        };
+        let mut data = CompanyData::default();
+        assert_eq!(evaluate_company_step(&step, &data), StepStatus::Missing);
+        data.filings = Some(vec![filing(DocumentType::Incorporation)]);
+        assert_eq!(evaluate_company_step(&step, &data), StepStatus::Partial);
+        data.filings.as_mut().unwrap().push(filing(DocumentType::ConfirmationStatement));
+        assert_eq!(evaluate_company_step(&step, &data), StepStatus::Satisfied);
+    }
+
+    #[test]
+    fn document_types() {
+        let step = CollectionStep::Document {
+            id: Uuid::new_v4(),
+            category: DocumentCategory::ProofOfIdentity,
+            allowed_types: vec![DocumentType::Passport].into_iter().collect::<BTreeSet<_>>()
+        };
+        let mut data = IndividualData::default();
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Missing);
+        data.documents = Some(vec![Document {
+            id: None,
+            category: DocumentCategory::ProofOfIdentity,
+            document_type: DocumentType::DrivingLicence
+        }]);
+        assert_eq!(status_of(step.clone(), &data), StepStatus::Partial);
+        data.documents.as_mut().unwrap().push(Document {
+            id: None,
+            category: DocumentCategory::ProofOfIdentity,
+            document_type: DocumentType::Passport
+        });
+        assert_eq!(status_of(step, &data), StepStatus::Satisfied);
+    }
+}
@@ -1,18 +1,66 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
 use uuid::Uuid;
 use serde_json;
 
-use api::{TaskType, CheckType, CollectionStep, DatePrecision};
+use chrono::{DateTime, Utc};
+
+use api::{TaskType, CheckType, CollectionStep, CollectionStepKind, DatePrecision, EntityData, EntityType, IndividualData};
+use error::ApiError;
+use satisfaction::{self, StepEvaluation, StepStatus};
+use address_history::{self, AddressHistoryAnalysis};
 
 
 table! {
     baskets (id) {
         id -> Uuid,
         contents -> Jsonb,
+        version -> Int4,
+    }
+}
+
+table! {
+    outbox (id) {
+        id -> Int8,
+        idempotency_key -> Text,
+        topic -> Text,
+        basket_id -> Uuid,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamptz>,
+        abandoned_at -> Nullable<Timestamptz>,
     }
 }
 
+table! {
+    webhooks (id) {
+        id -> Uuid,
+        tenant_id -> Text,
+        url -> Text,
+        secret -> Text,
+        events -> Array<Text>,
+        created_at -> Timestamptz,
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+table! {
+    webhook_deliveries (id) {
+        id -> Uuid,
+        webhook_id -> Uuid,
+        event_id -> Text,
+        event -> Text,
+        attempted_at -> Timestamptz,
+        response_status -> Nullable<Int4>,
+        error -> Nullable<Text>,
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Check {
     pub id: Uuid,
     pub task: TaskType,
@@ -21,6 +69,17 @@ pub struct Check {
 
 
 impl Check {
+    // Make sure this check can actually be used to complete its task
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.task.available_checks().contains(&self.check) {
+            Ok(())
+        } else {
+            Err(ApiError::invalid_input(format!(
+                "{:?} cannot be used for {:?}", self.check, self.task
+            )))
+        }
+    }
+
     pub fn calculate_collection_steps(&self) -> Vec<CollectionStep> {
         let mut result = Vec::new();
         match self.check {
@@ -37,21 +96,46 @@ impl Check {
             CheckType::DocumentVerification => {
                 result.push(CollectionStep::Nationality {});
             },
-            _ => unimplemented!()
+            CheckType::DocumentFetch => {
+                // Documents are fetched from third parties, which need
+                // to be able to match the individual and their address
+                result.push(CollectionStep::FullName {});
+                result.push(CollectionStep::Dob { precision: DatePrecision::YearMonthDay });
+                result.push(CollectionStep::AddressHistory { months: 0 });
+            },
+            CheckType::AdverseMediaScreen => {
+                result.push(CollectionStep::FullName {});
+                result.push(CollectionStep::Dob { precision: DatePrecision::Year });
+            },
+            CheckType::CompanyRegistry | CheckType::CompanyOwnership |
+            CheckType::CompanyFilings | CheckType::CompanyFilingPurchase => {
+                // Uniquely identify the company within its registry
+                result.push(CollectionStep::CompanyName {});
+                result.push(CollectionStep::RegistrationNumber {});
+                result.push(CollectionStep::Jurisdiction {});
+            },
+            CheckType::CompanyPepsAndSanctionsScreen => {
+                result.push(CollectionStep::CompanyName {});
+                result.push(CollectionStep::Jurisdiction {});
+            },
         }
         result
     }
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Profile {
     pub id: Uuid,
+    #[serde(default)]
+    pub entity_type: EntityType,
     pub possible_recipients: Vec<Uuid>,
     pub checks: Vec<Check>,
     pub selected_recipient: Option<Uuid>,
     pub extra_collection_steps: Vec<CollectionStep>,
-    pub calculated_collection_steps: Vec<CollectionStep>
+    pub calculated_collection_steps: Vec<CollectionStep>,
+    // The information collected about this profile so far
+    pub entity_data: Option<EntityData>
 }
 
 fn merge_collection_steps(into: &mut Vec<CollectionStep>, src: &[CollectionStep]) {
@@ -78,25 +162,250 @@ impl Profile {
         // Add any extra collection steps
         merge_collection_steps(&mut self.calculated_collection_steps, &self.extra_collection_steps);
     }
+
+    // The collection steps which are not yet fully satisfied by
+    // the collected data
+    pub fn outstanding_steps(&self) -> Vec<StepEvaluation> {
+        let today = Utc::today().naive_utc();
+        satisfaction::evaluate(&self.calculated_collection_steps, self.entity_data.as_ref(), today)
+            .into_iter()
+            .filter(|evaluation| evaluation.status != StepStatus::Satisfied)
+            .collect()
+    }
+
+    // Gaps, overlaps and other problems with the submitted address
+    // history, or `None` if no address history has been submitted.
+    pub fn address_history_analysis(&self) -> Option<AddressHistoryAnalysis> {
+        match self.entity_data {
+            Some(EntityData::IndividualData(IndividualData { address_history: Some(ref history), .. })) => {
+                Some(address_history::analyse(history, Utc::today().naive_utc()))
+            },
+            _ => None
+        }
+    }
+
+    // Replace the information collected about this profile, which
+    // must be for the same kind of entity as the profile.
+    pub fn set_entity_data(&mut self, data: EntityData) -> Result<(), ApiError> {
+        if data.entity_type() != self.entity_type {
+            return Err(ApiError::invalid_input(format!(
+                "Cannot submit {:?} data for a profile of type {:?}", data.entity_type(), self.entity_type
+            )));
+        }
+        self.entity_data = Some(data);
+        Ok(())
+    }
+
+    pub fn needs_information(&self) -> bool {
+        !self.outstanding_steps().is_empty()
+    }
+
+    pub fn add_check(&mut self, check: Check) -> Result<(), ApiError> {
+        check.validate()?;
+        if check.task.entity_type() != self.entity_type {
+            return Err(ApiError::invalid_input(format!(
+                "{:?} cannot be used for a profile of type {:?}", check.task, self.entity_type
+            )));
+        }
+        if self.checks.iter().any(|c| c.id == check.id || (c.task == check.task && c.check == check.check)) {
+            return Err(ApiError::conflict("Check already exists on this profile"));
+        }
+        self.checks.push(check);
+        self.recalculate_collection_steps();
+        Ok(())
+    }
+
+    pub fn add_extra_collection_step(&mut self, step: CollectionStep) {
+        merge_collection_steps(&mut self.extra_collection_steps, &[step]);
+        self.recalculate_collection_steps();
+    }
+
+    // Extra steps are merged, so there is at most one of each kind,
+    // apart from documents which are identified by ID.
+    pub fn remove_extra_collection_step(&mut self, kind: CollectionStepKind, document_id: Option<Uuid>) -> Result<(), ApiError> {
+        let num_steps = self.extra_collection_steps.len();
+        self.extra_collection_steps.retain(|step| match *step {
+            CollectionStep::Document { id, .. } => kind != CollectionStepKind::Document || Some(id) != document_id,
+            _ => step.kind() != kind
+        });
+        if self.extra_collection_steps.len() == num_steps {
+            return Err(ApiError::not_found("Collection step not found"));
+        }
+        self.recalculate_collection_steps();
+        Ok(())
+    }
+
+    pub fn remove_check(&mut self, check_id: Uuid) -> Result<Check, ApiError> {
+        let index = self.checks.iter()
+            .position(|c| c.id == check_id)
+            .ok_or_else(|| ApiError::not_found("Check ID not found"))?;
+        let check = self.checks.remove(index);
+        self.recalculate_collection_steps();
+        Ok(check)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct PublicArgs {
     pub from: Option<String>,
     pub bcc: Vec<String>,
+    // Selects which translation of the message template is used
+    #[serde(default)]
+    pub locale: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct PrivateArgs {
     pub from: Option<String>,
     pub bcc: Vec<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    // Extra values made available to the message template as `{{private.<name>}}`
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+// A single attempt to send a communication
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryAttempt {
+    pub attempted_at: DateTime<Utc>,
+    // The transport used, or `None` if there was no suitable transport
+    pub transport: Option<String>,
+    // Why the attempt failed, or `None` if it succeeded
+    pub error: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CommunicationKind {
+    Invite,
+    Reminder,
+    CompletionNotice
+}
+
+// Communications stored before kinds were recorded were all invites
+impl Default for CommunicationKind {
+    fn default() -> Self {
+        CommunicationKind::Invite
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CommunicationStatus {
+    // Still being prepared, and can be freely changed
+    Draft,
+    // Waiting to be sent
+    Queued,
+    // Claimed by a dispatcher, which is sending it
+    Sending,
+    Sent,
+    Failed,
+    Cancelled
+}
+
+impl Default for CommunicationStatus {
+    fn default() -> Self {
+        CommunicationStatus::Draft
+    }
+}
+
+impl CommunicationStatus {
+    // Whether a communication can move from this status to `to`.
+    // Failed communications may be queued again to retry them.
+    pub fn can_become(self, to: CommunicationStatus) -> bool {
+        use self::CommunicationStatus::*;
+        match (self, to) {
+            (Draft, Queued) | (Draft, Cancelled) |
+            (Queued, Sending) | (Queued, Cancelled) |
+            (Sending, Sent) | (Sending, Failed) |
+            (Failed, Queued) | (Failed, Cancelled) => true,
+            _ => false
+        }
+    }
+
+    // Whether the public arguments may still be changed
+    pub fn is_editable(self) -> bool {
+        match self {
+            CommunicationStatus::Draft | CommunicationStatus::Queued => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Communication {
+    #[serde(default)]
+    pub id: Uuid,
+    #[serde(default)]
+    pub kind: CommunicationKind,
+    #[serde(default)]
+    pub status: CommunicationStatus,
     pub recipient: Uuid,
+    // Set by the front-end
     pub public_args: PublicArgs,
+    // Set by the server, and never exposed to changes from the front-end
     pub private_args: PrivateArgs,
+    #[serde(default)]
+    pub delivery_attempts: Vec<DeliveryAttempt>,
+    // While sending, when the claim on the communication expires
+    #[serde(default)]
+    pub sending_until: Option<DateTime<Utc>>,
+}
+
+impl Communication {
+    pub fn set_status(&mut self, status: CommunicationStatus) -> Result<(), ApiError> {
+        if !self.status.can_become(status) {
+            return Err(ApiError::conflict(format!(
+                "Communication cannot change from {:?} to {:?}", self.status, status
+            )));
+        }
+        self.status = status;
+        Ok(())
+    }
+
+    // Claim a queued communication until `until`, so that nothing else
+    // sends it in the meantime. A claim which has expired, eg. because
+    // the process sending the communication died, may be taken over.
+    pub fn claim(&mut self, now: DateTime<Utc>, until: DateTime<Utc>) -> Result<(), ApiError> {
+        match self.status {
+            CommunicationStatus::Sending if self.sending_until.map_or(false, |t| t > now) => {
+                return Err(ApiError::conflict("Communication is already being sent"));
+            },
+            CommunicationStatus::Sending => {},
+            _ => self.set_status(CommunicationStatus::Sending)?
+        }
+        self.sending_until = Some(until);
+        Ok(())
+    }
+
+    // Record an attempt made under the claim which expires at `claimed_until`.
+    // The status is only updated if that claim is still held. Returns
+    // whether the communication has now been sent.
+    pub fn record_attempt(&mut self, claimed_until: DateTime<Utc>, attempt: DeliveryAttempt) -> bool {
+        let succeeded = attempt.error.is_none();
+        self.delivery_attempts.push(attempt);
+        if self.status != CommunicationStatus::Sending || self.sending_until != Some(claimed_until) {
+            return false;
+        }
+        self.status = if succeeded {
+            CommunicationStatus::Sent
+        } else {
+            CommunicationStatus::Failed
+        };
+        self.sending_until = None;
+        succeeded
+    }
+
+    pub fn set_public_args(&mut self, public_args: PublicArgs) -> Result<(), ApiError> {
+        if !self.status.is_editable() {
+            return Err(ApiError::conflict(format!(
+                "Communication can no longer be changed, as it is {:?}", self.status
+            )));
+        }
+        self.public_args = public_args;
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -117,33 +426,304 @@ pub struct Recipient {
     pub contact_method: ContactMethod,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// An inconsistency between the parts of a basket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceProblem {
+    DuplicateProfile { profile_id: Uuid },
+    DuplicateRecipient { recipient_id: Uuid },
+    DuplicateCheck { profile_id: Uuid, check_id: Uuid },
+    DuplicateCommunication { communication_id: Uuid },
+    // A profile lists a recipient which is not in the basket
+    UnknownPossibleRecipient { profile_id: Uuid, recipient_id: Uuid },
+    // A profile has selected a recipient which is not in the basket
+    UnknownSelectedRecipient { profile_id: Uuid, recipient_id: Uuid },
+    // A profile has selected a recipient which it does not list
+    // as a possible recipient
+    SelectedRecipientNotPossible { profile_id: Uuid, recipient_id: Uuid },
+    // A communication is addressed to a recipient which is not in the basket
+    UnknownCommunicationRecipient { recipient_id: Uuid },
+}
+
+impl fmt::Display for ReferenceProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReferenceProblem::*;
+        match *self {
+            DuplicateProfile { profile_id } =>
+                write!(f, "Profile {} appears more than once", profile_id),
+            DuplicateRecipient { recipient_id } =>
+                write!(f, "Recipient {} appears more than once", recipient_id),
+            DuplicateCheck { profile_id, check_id } =>
+                write!(f, "Check {} appears more than once on profile {}", check_id, profile_id),
+            DuplicateCommunication { communication_id } =>
+                write!(f, "Communication {} appears more than once", communication_id),
+            UnknownPossibleRecipient { profile_id, recipient_id } =>
+                write!(f, "Profile {} refers to unknown recipient {}", profile_id, recipient_id),
+            UnknownSelectedRecipient { profile_id, recipient_id } =>
+                write!(f, "Profile {} has selected unknown recipient {}", profile_id, recipient_id),
+            SelectedRecipientNotPossible { profile_id, recipient_id } =>
+                write!(f, "Profile {} has selected recipient {}, which is not one of its possible recipients", profile_id, recipient_id),
+            UnknownCommunicationRecipient { recipient_id } =>
+                write!(f, "A communication is addressed to unknown recipient {}", recipient_id),
+        }
+    }
+}
+
+// Communications stored before they had IDs are read with the nil ID.
+// Derive an ID from the communication and its position instead, so
+// that the same ID is used every time the basket is loaded until it
+// is saved again.
+fn legacy_communication_id(index: usize, communication: &Communication) -> Uuid {
+    let data = serde_json::to_string(communication).expect("Failed to serialize communication");
+    Uuid::new_v5(&LEGACY_COMMUNICATION_NAMESPACE, &format!("{}:{}", index, data))
+}
+
+lazy_static! {
+    // Namespace of the name-based IDs given to legacy communications
+    static ref LEGACY_COMMUNICATION_NAMESPACE: Uuid = "3b7e1f0a-9c4d-4a52-8e6b-d1f2a3c4b5e6".parse().unwrap();
+}
+
+// The contents of a basket as stored before every profile recorded its
+// entity type, and every communication had an ID. Upgraded to
+// `BasketContentsV2` when it is loaded.
+#[derive(Deserialize, Debug)]
 pub struct BasketContentsV1 {
     pub profiles_to_check: Vec<Profile>,
     pub communications: Vec<Communication>,
-    pub recipients: Vec<Recipient>
+    pub recipients: Vec<Recipient>,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub submitted_at: Option<DateTime<Utc>>,
 }
 
 impl BasketContentsV1 {
+    fn into_v2(self) -> BasketContentsV2 {
+        let mut profiles_to_check = self.profiles_to_check;
+        for profile in &mut profiles_to_check {
+            // Profiles without an entity type were read as individuals,
+            // even if company data had been submitted for them.
+            let entity_type = profile.entity_data.as_ref().map(EntityData::entity_type);
+            if let Some(entity_type) = entity_type {
+                profile.entity_type = entity_type;
+            }
+        }
+        let mut communications = self.communications;
+        for (index, communication) in communications.iter_mut().enumerate() {
+            if communication.id.is_nil() {
+                communication.id = legacy_communication_id(index, communication);
+            }
+        }
+        BasketContentsV2 {
+            profiles_to_check,
+            communications,
+            recipients: self.recipients,
+            tenant_id: self.tenant_id,
+            submitted_at: self.submitted_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BasketContentsV2 {
+    pub profiles_to_check: Vec<Profile>,
+    pub communications: Vec<Communication>,
+    pub recipients: Vec<Recipient>,
+    // The tenant which owns the basket, whose webhooks receive its events
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    // When the checkout was submitted, or `None` if it has not been
+    #[serde(default)]
+    pub submitted_at: Option<DateTime<Utc>>,
+}
+
+impl BasketContentsV2 {
+    // Find every reference which does not resolve, and every ID
+    // which is used more than once.
+    pub fn validate(&self) -> Vec<ReferenceProblem> {
+        use self::ReferenceProblem::*;
+        let mut problems = Vec::new();
+
+        let mut recipient_ids = HashSet::new();
+        for recipient in &self.recipients {
+            if !recipient_ids.insert(recipient.id) {
+                problems.push(DuplicateRecipient { recipient_id: recipient.id });
+            }
+        }
+
+        let mut profile_ids = HashSet::new();
+        for profile in &self.profiles_to_check {
+            let profile_id = profile.id;
+            if !profile_ids.insert(profile_id) {
+                problems.push(DuplicateProfile { profile_id });
+            }
+            let mut check_ids = HashSet::new();
+            for check in &profile.checks {
+                if !check_ids.insert(check.id) {
+                    problems.push(DuplicateCheck { profile_id, check_id: check.id });
+                }
+            }
+            for &recipient_id in &profile.possible_recipients {
+                if !recipient_ids.contains(&recipient_id) {
+                    problems.push(UnknownPossibleRecipient { profile_id, recipient_id });
+                }
+            }
+            if let Some(recipient_id) = profile.selected_recipient {
+                if !recipient_ids.contains(&recipient_id) {
+                    problems.push(UnknownSelectedRecipient { profile_id, recipient_id });
+                } else if !profile.possible_recipients.contains(&recipient_id) {
+                    problems.push(SelectedRecipientNotPossible { profile_id, recipient_id });
+                }
+            }
+        }
+
+        let mut communication_ids = HashSet::new();
+        for communication in &self.communications {
+            if !communication_ids.insert(communication.id) {
+                problems.push(DuplicateCommunication { communication_id: communication.id });
+            }
+            if !recipient_ids.contains(&communication.recipient) {
+                problems.push(UnknownCommunicationRecipient { recipient_id: communication.recipient });
+            }
+        }
+        problems
+    }
+
     pub fn find_profile_mut(&mut self, profile_id: Uuid) -> Option<&mut Profile> {
         self.profiles_to_check.iter_mut()
             .filter(|p| p.id == profile_id)
             .next()
     }
+
+    fn profile_index(&self, profile_id: Uuid) -> Result<usize, ApiError> {
+        self.profiles_to_check.iter()
+            .position(|p| p.id == profile_id)
+            .ok_or_else(|| ApiError::not_found("Profile ID not found"))
+    }
+
+    pub fn add_profile(&mut self, mut profile: Profile) -> Result<(), ApiError> {
+        if self.profiles_to_check.iter().any(|p| p.id == profile.id) {
+            return Err(ApiError::conflict("Profile ID already exists in this basket"));
+        }
+        profile.recalculate_collection_steps();
+        self.profiles_to_check.push(profile);
+        Ok(())
+    }
+
+    pub fn remove_profile(&mut self, profile_id: Uuid) -> Result<Profile, ApiError> {
+        let index = self.profile_index(profile_id)?;
+        Ok(self.profiles_to_check.remove(index))
+    }
+
+    // Move a profile so that it ends up at `new_index`
+    pub fn move_profile(&mut self, profile_id: Uuid, new_index: usize) -> Result<(), ApiError> {
+        let index = self.profile_index(profile_id)?;
+        if new_index >= self.profiles_to_check.len() {
+            return Err(ApiError::invalid_input("Profile index out of range"));
+        }
+        let profile = self.profiles_to_check.remove(index);
+        self.profiles_to_check.insert(new_index, profile);
+        Ok(())
+    }
+
+    pub fn find_communication_mut(&mut self, communication_id: Uuid) -> Result<&mut Communication, ApiError> {
+        self.communications.iter_mut()
+            .filter(|c| c.id == communication_id)
+            .next()
+            .ok_or_else(|| ApiError::not_found("Communication ID not found"))
+    }
+
+    pub fn find_recipient_mut(&mut self, recipient_id: Uuid) -> Option<&mut Recipient> {
+        self.recipients.iter_mut()
+            .filter(|r| r.id == recipient_id)
+            .next()
+    }
+
+    pub fn add_recipient(&mut self, recipient: Recipient) -> Result<(), ApiError> {
+        if self.recipients.iter().any(|r| r.id == recipient.id) {
+            return Err(ApiError::conflict("Recipient ID already exists in this basket"));
+        }
+        self.recipients.push(recipient);
+        Ok(())
+    }
+
+    // Whether any profile or communication refers to the recipient
+    pub fn is_recipient_in_use(&self, recipient_id: Uuid) -> bool {
+        self.profiles_to_check.iter().any(|p| {
+            p.selected_recipient == Some(recipient_id) || p.possible_recipients.contains(&recipient_id)
+        }) || self.communications.iter().any(|c| c.recipient == recipient_id)
+    }
+
+    // Remove a recipient. If it is still in use, this fails unless
+    // `clear_references` is set, in which case it is deselected from
+    // every profile, and any draft communications addressed to it are
+    // removed. Communications which have been queued or sent are a record
+    // of what happened, so a recipient with any of those cannot be removed.
+    pub fn remove_recipient(&mut self, recipient_id: Uuid, clear_references: bool) -> Result<Recipient, ApiError> {
+        let index = self.recipients.iter()
+            .position(|r| r.id == recipient_id)
+            .ok_or_else(|| ApiError::not_found("Recipient ID not found"))?;
+        if self.is_recipient_in_use(recipient_id) {
+            if !clear_references {
+                return Err(ApiError::conflict("Recipient is still in use"));
+            }
+            if self.communications.iter().any(|c| c.recipient == recipient_id && c.status != CommunicationStatus::Draft) {
+                return Err(ApiError::conflict("Recipient has communications which are no longer drafts"));
+            }
+            for profile in &mut self.profiles_to_check {
+                profile.possible_recipients.retain(|&id| id != recipient_id);
+                if profile.selected_recipient == Some(recipient_id) {
+                    profile.selected_recipient = None;
+                }
+            }
+            self.communications.retain(|c| c.recipient != recipient_id);
+        }
+        Ok(self.recipients.remove(index))
+    }
+
+    // Assign the basket to a tenant. A basket can never be moved to a
+    // different tenant, since that tenant would receive its events.
+    pub fn set_tenant(&mut self, tenant_id: &str) -> Result<(), ApiError> {
+        match self.tenant_id {
+            Some(ref existing) if existing != tenant_id => {
+                return Err(ApiError::conflict("Basket already belongs to a different tenant"));
+            },
+            _ => {}
+        }
+        self.tenant_id = Some(tenant_id.into());
+        Ok(())
+    }
+
+    // Mark the checkout as submitted. It can only be submitted once,
+    // and only when there is something to check.
+    pub fn submit(&mut self) -> Result<(), ApiError> {
+        if self.submitted_at.is_some() {
+            return Err(ApiError::conflict("Checkout has already been submitted"));
+        }
+        if self.profiles_to_check.is_empty() {
+            return Err(ApiError::invalid_input("Checkout has no profiles to check"));
+        }
+        self.submitted_at = Some(Utc::now());
+        Ok(())
+    }
 }
 
 version_json_type!(
-    #[derive(Debug, Default)]
+    #[derive(Debug, Default, Clone)]
     basket_contents BasketContents {
-        V1 => BasketContentsV1 {}
+        V1 => BasketContentsV1 {
+            basket_contents.into_v2()
+        },
+        V2 => BasketContentsV2 {}
     }
 );
 
-#[derive(Queryable, Insertable, Default, Debug)]
+#[derive(Queryable, Insertable, Default, Debug, Clone)]
 #[table_name="baskets"]
 pub struct Basket {
     pub id: Uuid,
-    pub contents: BasketContents
+    pub contents: BasketContents,
+    // Incremented every time the basket is updated
+    pub version: i32
 }
 
 #[cfg(test)]
@@ -178,4 +758,459 @@ mod tests {
 
         assert_eq!(actual_collection_steps, expected_collection_steps);
     }
+
+    fn steps_for(check: CheckType) -> HashSet<CollectionStep> {
+        let check = Check {
+            id: Default::default(),
+            task: TaskType::IndividualVerifyIdentity,
+            check,
+        };
+        check.calculate_collection_steps().into_iter().collect()
+    }
+
+    fn individual_steps(dob_precision: DatePrecision) -> HashSet<CollectionStep> {
+        vec![
+            CollectionStep::FullName {},
+            CollectionStep::Dob { precision: dob_precision },
+        ].into_iter().collect()
+    }
+
+    fn company_registry_steps() -> HashSet<CollectionStep> {
+        vec![
+            CollectionStep::CompanyName {},
+            CollectionStep::RegistrationNumber {},
+            CollectionStep::Jurisdiction {},
+        ].into_iter().collect()
+    }
+
+    #[test]
+    fn identity_check_steps() {
+        let mut expected = individual_steps(DatePrecision::YearMonthDay);
+        expected.insert(CollectionStep::AddressHistory { months: 0 });
+        assert_eq!(steps_for(CheckType::IdentityCheck), expected);
+    }
+
+    #[test]
+    fn document_verification_steps() {
+        let expected = vec![CollectionStep::Nationality {}].into_iter().collect();
+        assert_eq!(steps_for(CheckType::DocumentVerification), expected);
+    }
+
+    #[test]
+    fn document_fetch_steps() {
+        let mut expected = individual_steps(DatePrecision::YearMonthDay);
+        expected.insert(CollectionStep::AddressHistory { months: 0 });
+        assert_eq!(steps_for(CheckType::DocumentFetch), expected);
+    }
+
+    #[test]
+    fn peps_screen_steps() {
+        let mut expected = individual_steps(DatePrecision::YearMonthDay);
+        expected.insert(CollectionStep::Nationality {});
+        assert_eq!(steps_for(CheckType::PepsScreen), expected);
+    }
+
+    #[test]
+    fn sanctions_screen_steps() {
+        let mut expected = individual_steps(DatePrecision::YearMonthDay);
+        expected.insert(CollectionStep::Nationality {});
+        assert_eq!(steps_for(CheckType::SanctionsScreen), expected);
+    }
+
+    #[test]
+    fn peps_and_sanctions_screen_steps() {
+        let mut expected = individual_steps(DatePrecision::YearMonthDay);
+        expected.insert(CollectionStep::Nationality {});
+        assert_eq!(steps_for(CheckType::PepsAndSanctionsScreen), expected);
+    }
+
+    #[test]
+    fn adverse_media_screen_steps() {
+        assert_eq!(steps_for(CheckType::AdverseMediaScreen), individual_steps(DatePrecision::Year));
+    }
+
+    #[test]
+    fn company_registry_check_steps() {
+        assert_eq!(steps_for(CheckType::CompanyRegistry), company_registry_steps());
+    }
+
+    #[test]
+    fn company_ownership_steps() {
+        assert_eq!(steps_for(CheckType::CompanyOwnership), company_registry_steps());
+    }
+
+    #[test]
+    fn company_filings_steps() {
+        assert_eq!(steps_for(CheckType::CompanyFilings), company_registry_steps());
+    }
+
+    #[test]
+    fn company_filing_purchase_steps() {
+        assert_eq!(steps_for(CheckType::CompanyFilingPurchase), company_registry_steps());
+    }
+
+    #[test]
+    fn company_peps_and_sanctions_screen_steps() {
+        let expected = vec![
+            CollectionStep::CompanyName {},
+            CollectionStep::Jurisdiction {},
+        ].into_iter().collect();
+        assert_eq!(steps_for(CheckType::CompanyPepsAndSanctionsScreen), expected);
+    }
+
+    #[test]
+    fn add_and_remove_checks() {
+        let mut profile = Profile::default();
+        let check = Check {
+            id: Uuid::new_v4(),
+            task: TaskType::IndividualVerifyIdentity,
+            check: CheckType::IdentityCheck,
+        };
+        profile.add_check(check.clone()).unwrap();
+        assert!(!profile.calculated_collection_steps.is_empty());
+
+        // The same check cannot be added twice
+        assert!(profile.add_check(Check { id: Uuid::new_v4(), ..check.clone() }).is_err());
+
+        // Incompatible task/check pairs are rejected
+        assert!(profile.add_check(Check {
+            id: Uuid::new_v4(),
+            task: TaskType::IndividualVerifyIdentity,
+            check: CheckType::CompanyRegistry,
+        }).is_err());
+
+        profile.remove_check(check.id).unwrap();
+        assert!(profile.checks.is_empty());
+        assert!(profile.calculated_collection_steps.is_empty());
+    }
+
+    #[test]
+    fn every_compatible_check_can_be_added() {
+        for &entity_type in &[EntityType::Individual, EntityType::Company] {
+            for task in TaskType::all_for(entity_type) {
+                for &check in task.available_checks() {
+                    let mut profile = Profile { entity_type, ..Default::default() };
+                    profile.add_check(Check { id: Uuid::new_v4(), task, check }).unwrap();
+                    assert!(!profile.calculated_collection_steps.is_empty(), "{:?} has no collection steps", check);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn validate_references() {
+        let recipient_id = Uuid::new_v4();
+        let profile_id = Uuid::new_v4();
+        let unknown_id = Uuid::new_v4();
+        let mut contents = BasketContentsV2::default();
+        contents.recipients.push(Recipient {
+            id: recipient_id,
+            name: "Ada Lovelace".into(),
+            contact_method: ContactMethod::Email { address: "ada@example.com".into() }
+        });
+        contents.profiles_to_check.push(Profile {
+            id: profile_id,
+            possible_recipients: vec![unknown_id],
+            selected_recipient: Some(recipient_id),
+            ..Default::default()
+        });
+        contents.profiles_to_check.push(Profile { id: profile_id, ..Default::default() });
+        contents.communications.push(Communication {
+            recipient: unknown_id,
+            ..Default::default()
+        });
+
+        assert_eq!(contents.validate(), vec![
+            ReferenceProblem::UnknownPossibleRecipient { profile_id, recipient_id: unknown_id },
+            ReferenceProblem::SelectedRecipientNotPossible { profile_id, recipient_id },
+            ReferenceProblem::DuplicateProfile { profile_id },
+            ReferenceProblem::UnknownCommunicationRecipient { recipient_id: unknown_id },
+        ]);
+
+        contents.profiles_to_check.pop();
+        contents.profiles_to_check[0].possible_recipients = vec![recipient_id];
+        contents.communications.clear();
+        assert_eq!(contents.validate(), vec![]);
+    }
+
+    #[test]
+    fn communication_lifecycle() {
+        let mut communication = Communication::default();
+        communication.set_public_args(PublicArgs { from: Some("Acme".into()), bcc: vec![], locale: None }).unwrap();
+        assert!(communication.set_status(CommunicationStatus::Sent).is_err());
+        communication.set_status(CommunicationStatus::Queued).unwrap();
+        assert!(communication.set_status(CommunicationStatus::Failed).is_err());
+        communication.set_status(CommunicationStatus::Sending).unwrap();
+        communication.set_status(CommunicationStatus::Failed).unwrap();
+        assert!(communication.set_public_args(PublicArgs::default()).is_err());
+        communication.set_status(CommunicationStatus::Queued).unwrap();
+        communication.set_status(CommunicationStatus::Sending).unwrap();
+        communication.set_status(CommunicationStatus::Sent).unwrap();
+        assert!(communication.set_status(CommunicationStatus::Cancelled).is_err());
+        assert_eq!(communication.public_args.from, Some("Acme".into()));
+    }
+
+    #[test]
+    fn claim_communication() {
+        use chrono::Duration;
+
+        let now = Utc::now();
+        let mut communication = Communication::default();
+        assert!(communication.claim(now, now + Duration::minutes(1)).is_err());
+        communication.set_status(CommunicationStatus::Queued).unwrap();
+        communication.claim(now, now + Duration::minutes(1)).unwrap();
+
+        // The claim is held until it expires
+        assert!(communication.claim(now, now + Duration::minutes(2)).is_err());
+        let later = now + Duration::minutes(1);
+        communication.claim(later, later + Duration::minutes(1)).unwrap();
+
+        // Attempts made under an expired claim do not change the status
+        let attempt = DeliveryAttempt { attempted_at: later, transport: None, error: None };
+        assert!(!communication.record_attempt(now + Duration::minutes(1), attempt.clone()));
+        assert_eq!(communication.status, CommunicationStatus::Sending);
+        assert!(communication.record_attempt(later + Duration::minutes(1), attempt));
+        assert_eq!(communication.status, CommunicationStatus::Sent);
+        assert_eq!(communication.delivery_attempts.len(), 2);
+    }
+
+    #[test]
+    fn remove_recipient_in_use() {
+        let recipient_id = Uuid::new_v4();
+        let mut contents = BasketContentsV2::default();
+        contents.add_recipient(Recipient {
+            id: recipient_id,
+            name: "Ada Lovelace".into(),
+            contact_method: ContactMethod::Email { address: "ada@example.com".into() }
+        }).unwrap();
+        contents.add_profile(Profile {
+            id: Uuid::new_v4(),
+            possible_recipients: vec![recipient_id],
+            selected_recipient: Some(recipient_id),
+            ..Default::default()
+        }).unwrap();
+        contents.communications.push(Communication {
+            recipient: recipient_id,
+            ..Default::default()
+        });
+
+        contents.communications.push(Communication {
+            id: Uuid::new_v4(),
+            recipient: recipient_id,
+            status: CommunicationStatus::Sent,
+            ..Default::default()
+        });
+
+        assert!(contents.remove_recipient(recipient_id, false).is_err());
+        assert_eq!(contents.recipients.len(), 1);
+
+        // Sent communications are kept, so the recipient must be too
+        assert!(contents.remove_recipient(recipient_id, true).is_err());
+        assert_eq!(contents.communications.len(), 2);
+
+        contents.communications.pop();
+        contents.remove_recipient(recipient_id, true).unwrap();
+        assert!(contents.recipients.is_empty());
+        assert!(contents.communications.is_empty());
+        assert!(contents.profiles_to_check[0].possible_recipients.is_empty());
+        assert_eq!(contents.profiles_to_check[0].selected_recipient, None);
+        assert!(contents.remove_recipient(recipient_id, true).is_err());
+    }
+
+    #[test]
+    fn checks_match_entity_type() {
+        let mut profile = Profile::default();
+        let check = Check {
+            id: Uuid::new_v4(),
+            task: TaskType::CompanyVerifyIdentity,
+            check: CheckType::CompanyRegistry,
+        };
+        assert!(profile.add_check(check.clone()).is_err());
+
+        profile.entity_type = EntityType::Company;
+        profile.add_check(check).unwrap();
+        assert!(profile.add_check(Check {
+            id: Uuid::new_v4(),
+            task: TaskType::IndividualVerifyIdentity,
+            check: CheckType::IdentityCheck,
+        }).is_err());
+    }
+
+    #[test]
+    fn entity_data_matches_entity_type() {
+        use api::CompanyData;
+
+        let mut profile = Profile::default();
+        assert!(profile.set_entity_data(EntityData::CompanyData(CompanyData::default())).is_err());
+        profile.set_entity_data(EntityData::IndividualData(IndividualData::default())).unwrap();
+        assert_eq!(profile.entity_data.as_ref().map(EntityData::entity_type), Some(EntityType::Individual));
+    }
+
+    #[test]
+    fn entity_type_defaults_to_individual() {
+        use serde_json;
+
+        let profile: Profile = serde_json::from_str(r#"{
+            "id": "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+            "possible_recipients": [],
+            "checks": [],
+            "selected_recipient": null,
+            "extra_collection_steps": [],
+            "calculated_collection_steps": [],
+            "entity_data": null
+        }"#).unwrap();
+        assert_eq!(profile.entity_type, EntityType::Individual);
+    }
+
+    #[test]
+    fn upgrade_company_profiles() {
+        use serde_json;
+
+        let contents: BasketContents = serde_json::from_str(r#"{"V1": {
+            "profiles_to_check": [{
+                "id": "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+                "possible_recipients": [],
+                "checks": [],
+                "selected_recipient": null,
+                "extra_collection_steps": [],
+                "calculated_collection_steps": [],
+                "entity_data": { "entity_type": "COMPANY_DATA", "legal_name": "Acme Widgets Ltd" }
+            }],
+            "communications": [],
+            "recipients": []
+        }}"#).unwrap();
+        let mut profile = contents.0.profiles_to_check[0].clone();
+        assert_eq!(profile.entity_type, EntityType::Company);
+
+        // Company data can still be submitted for the profile
+        profile.set_entity_data(EntityData::CompanyData(Default::default())).unwrap();
+
+        // Upgraded contents are stored as the latest version
+        let json = serde_json::to_value(&contents).unwrap();
+        assert!(json.get("V2").is_some());
+    }
+
+    #[test]
+    fn upgrade_communications_without_ids() {
+        use serde_json;
+
+        let json = r#"{"V1": {
+            "profiles_to_check": [],
+            "communications": [
+                {
+                    "recipient": "0e8f5b3c-6a2d-4c71-9f04-5d3b2a1e7c68",
+                    "public_args": { "from": null, "bcc": [] },
+                    "private_args": { "from": null, "bcc": [] }
+                },
+                {
+                    "recipient": "0e8f5b3c-6a2d-4c71-9f04-5d3b2a1e7c68",
+                    "public_args": { "from": null, "bcc": [] },
+                    "private_args": { "from": null, "bcc": [] }
+                }
+            ],
+            "recipients": [{
+                "id": "0e8f5b3c-6a2d-4c71-9f04-5d3b2a1e7c68",
+                "name": "Ada Lovelace",
+                "contact_method": { "email": { "address": "ada@example.com" } }
+            }]
+        }}"#;
+        let contents: BasketContents = serde_json::from_str(json).unwrap();
+        let ids: Vec<_> = contents.0.communications.iter().map(|c| c.id).collect();
+        assert!(ids.iter().all(|id| id.get_version_num() == 5));
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(contents.0.validate(), vec![]);
+
+        // The same IDs are used each time the basket is loaded
+        let reloaded: BasketContents = serde_json::from_str(json).unwrap();
+        let reloaded_ids: Vec<_> = reloaded.0.communications.iter().map(|c| c.id).collect();
+        assert_eq!(ids, reloaded_ids);
+    }
+
+    #[test]
+    fn add_and_remove_extra_collection_steps() {
+        let mut profile = Profile::default();
+        profile.add_extra_collection_step(CollectionStep::AddressHistory { months: 12 });
+        profile.add_extra_collection_step(CollectionStep::AddressHistory { months: 36 });
+        profile.add_extra_collection_step(CollectionStep::Nationality {});
+        assert_eq!(profile.extra_collection_steps, vec![
+            CollectionStep::AddressHistory { months: 36 },
+            CollectionStep::Nationality {},
+        ]);
+        assert_eq!(profile.calculated_collection_steps, profile.extra_collection_steps);
+
+        profile.remove_extra_collection_step(CollectionStepKind::AddressHistory, None).unwrap();
+        assert_eq!(profile.calculated_collection_steps, vec![CollectionStep::Nationality {}]);
+        assert!(profile.remove_extra_collection_step(CollectionStepKind::AddressHistory, None).is_err());
+    }
+
+    #[test]
+    fn remove_extra_document_steps() {
+        use api::DocumentCategory;
+
+        let document_id = Uuid::new_v4();
+        let mut profile = Profile::default();
+        profile.add_extra_collection_step(CollectionStep::Document {
+            id: document_id,
+            category: DocumentCategory::ProofOfAddress,
+            allowed_types: Default::default()
+        });
+        profile.add_extra_collection_step(CollectionStep::AddressHistory { months: 12 });
+
+        // Only document steps are removed by their ID
+        profile.remove_extra_collection_step(CollectionStepKind::AddressHistory, Some(document_id)).unwrap();
+        assert_eq!(profile.extra_collection_steps.len(), 1);
+        assert_eq!(profile.extra_collection_steps[0].kind(), CollectionStepKind::Document);
+
+        // A document step cannot be removed without its ID
+        assert!(profile.remove_extra_collection_step(CollectionStepKind::Document, None).is_err());
+        profile.remove_extra_collection_step(CollectionStepKind::Document, Some(document_id)).unwrap();
+        assert!(profile.extra_collection_steps.is_empty());
+    }
+
+    #[test]
+    fn submitted_data_satisfies_steps() {
+        use api::IndividualData;
+
+        let mut profile = Profile::default();
+        profile.add_extra_collection_step(CollectionStep::FullName {});
+        profile.add_extra_collection_step(CollectionStep::Nationality {});
+        assert!(profile.needs_information());
+
+        let mut data = IndividualData::default();
+        data.personal_details.name.given_names = Some(vec!["Ada".into()]);
+        data.personal_details.name.family_name = Some("Lovelace".into());
+        profile.entity_data = Some(EntityData::IndividualData(data.clone()));
+        let outstanding: Vec<_> = profile.outstanding_steps().into_iter().map(|e| e.step).collect();
+        assert_eq!(outstanding, vec![CollectionStep::Nationality {}]);
+
+        data.personal_details.nationality = Some("GB".into());
+        profile.entity_data = Some(EntityData::IndividualData(data));
+        assert!(!profile.needs_information());
+    }
+
+    fn profile_ids(contents: &BasketContentsV2) -> Vec<Uuid> {
+        contents.profiles_to_check.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn add_move_and_remove_profiles() {
+        let ids: Vec<_> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut contents = BasketContentsV2::default();
+        for &id in &ids {
+            contents.add_profile(Profile { id, ..Default::default() }).unwrap();
+        }
+
+        // Duplicate IDs are rejected
+        assert!(contents.add_profile(Profile { id: ids[1], ..Default::default() }).is_err());
+
+        contents.move_profile(ids[0], 2).unwrap();
+        assert_eq!(profile_ids(&contents), vec![ids[1], ids[2], ids[0]]);
+
+        // Out of range moves are rejected
+        assert!(contents.move_profile(ids[0], 3).is_err());
+
+        contents.remove_profile(ids[2]).unwrap();
+        assert_eq!(profile_ids(&contents), vec![ids[1], ids[0]]);
+        assert!(contents.remove_profile(ids[2]).is_err());
+    }
 }
@@ -0,0 +1,437 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use hyper::{self, Client, Url};
+use hyper::header::{ContentType, Headers};
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector};
+use hyper_rustls::TlsClient;
+use serde_json;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use database::interface::Database;
+use error::ApiError;
+use outbox::{Outbox, OutboxHandler, OutboxMessage};
+use schema::{BasketContentsV2, webhooks, webhook_deliveries};
+
+// Webhooks let a tenant's services find out about changes to its
+// baskets without polling. Events are recorded in the outbox along
+// with the change which caused them, and the relay then posts them
+// to every webhook which the tenant has registered for that event.
+
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub enum WebhookEvent {
+    #[serde(rename = "basket.created")]
+    BasketCreated,
+    #[serde(rename = "profile.added")]
+    ProfileAdded,
+    #[serde(rename = "recipient.selected")]
+    RecipientSelected,
+    #[serde(rename = "checkout.submitted")]
+    CheckoutSubmitted,
+    #[serde(rename = "communication.sent")]
+    CommunicationSent,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            WebhookEvent::BasketCreated => "basket.created",
+            WebhookEvent::ProfileAdded => "profile.added",
+            WebhookEvent::RecipientSelected => "recipient.selected",
+            WebhookEvent::CheckoutSubmitted => "checkout.submitted",
+            WebhookEvent::CommunicationSent => "communication.sent",
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "basket.created" => WebhookEvent::BasketCreated,
+            "profile.added" => WebhookEvent::ProfileAdded,
+            "recipient.selected" => WebhookEvent::RecipientSelected,
+            "checkout.submitted" => WebhookEvent::CheckoutSubmitted,
+            "communication.sent" => WebhookEvent::CommunicationSent,
+            other => return Err(format!("Unknown webhook event `{}`", other))
+        })
+    }
+}
+
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name="webhooks"]
+pub struct Webhook {
+    pub id: Uuid,
+    pub tenant_id: String,
+    pub url: String,
+    // Used to sign each payload, so the receiver can check where it
+    // came from. It is never returned by the API.
+    pub secret: String,
+    // The names of the events to deliver
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub fn new(tenant_id: &str, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<Webhook, ApiError> {
+        let tenant_id = normalize_tenant_id(tenant_id).map_err(|e| e.at("tenantId"))?;
+        // Payloads describe customers' baskets, so they are only sent over
+        // TLS. Whether the host is a public address is only checked when
+        // connecting, since a name may resolve differently by then.
+        match Url::parse(url) {
+            Ok(ref parsed) if parsed.scheme() == "https" && parsed.host().is_some() => {},
+            _ => return Err(ApiError::invalid_input("Expected an absolute https:// URL").at("url"))
+        }
+        if secret.len() < 16 {
+            return Err(ApiError::invalid_input("Secret must be at least 16 characters").at("secret"));
+        }
+        if events.is_empty() {
+            return Err(ApiError::invalid_input("At least one event is required").at("events"));
+        }
+        let mut names: Vec<String> = events.iter().map(|event| event.as_str().to_string()).collect();
+        names.sort();
+        names.dedup();
+        Ok(Webhook {
+            id: Uuid::new_v4(),
+            tenant_id,
+            url: url.into(),
+            secret: secret.into(),
+            events: names,
+            created_at: Utc::now(),
+        })
+    }
+
+    // Unrecognised names are skipped, in case an event is retired
+    pub fn subscribed_events(&self) -> Vec<WebhookEvent> {
+        self.events.iter().filter_map(|name| name.parse().ok()).collect()
+    }
+
+    pub fn is_subscribed(&self, event: WebhookEvent) -> bool {
+        self.events.iter().any(|name| name == event.as_str())
+    }
+}
+
+// A single attempt to deliver an event to a webhook
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name="webhook_deliveries"]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    // The same for every attempt to deliver the same event
+    pub event_id: String,
+    pub event: String,
+    pub attempted_at: DateTime<Utc>,
+    // `None` if no response was received
+    pub response_status: Option<i32>,
+    // Why the attempt failed, or `None` if it succeeded
+    pub error: Option<String>,
+}
+
+pub fn normalize_tenant_id(tenant_id: &str) -> Result<String, ApiError> {
+    let tenant_id = tenant_id.trim();
+    if tenant_id.is_empty() {
+        Err(ApiError::invalid_input("Tenant ID must not be empty"))
+    } else {
+        Ok(tenant_id.into())
+    }
+}
+
+
+// Topic of the outbox messages which carry webhook events
+pub const WEBHOOK_EVENT: &str = "webhook.event";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EventEnvelope {
+    tenant_id: String,
+    event: WebhookEvent,
+    basket_id: Uuid,
+    occurred_at: DateTime<Utc>,
+    data: serde_json::Value,
+}
+
+// The body posted to each webhook
+#[derive(Serialize, Debug)]
+struct EventPayload<'a> {
+    // Identifies the event, so that receivers can discard duplicates
+    id: &'a str,
+    event: WebhookEvent,
+    tenant_id: &'a str,
+    basket_id: Uuid,
+    occurred_at: DateTime<Utc>,
+    data: &'a serde_json::Value,
+}
+
+// Record an event against the basket being changed. Events are only
+// delivered once the change is committed, and only for baskets which
+// belong to a tenant.
+pub fn emit(outbox: &mut Outbox, contents: &BasketContentsV2, basket_id: Uuid, event: WebhookEvent, data: serde_json::Value) {
+    if let Some(ref tenant_id) = contents.tenant_id {
+        let envelope = EventEnvelope {
+            tenant_id: tenant_id.clone(),
+            event,
+            basket_id,
+            occurred_at: Utc::now(),
+            data,
+        };
+        outbox.push(WEBHOOK_EVENT, serde_json::to_value(envelope).expect("Failed to serialize webhook event"));
+    }
+}
+
+fn hmac_sha256_hex(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new(secret.as_bytes());
+    mac.input(message.as_bytes());
+    mac.result().code().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Sign a payload as `sha256=<hex HMAC of "<timestamp>.<body>">`. The
+// timestamp is included so that receivers can reject old payloads
+// which are replayed.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    format!("sha256={}", hmac_sha256_hex(secret, &format!("{}.{}", timestamp, body)))
+}
+
+
+// Webhook URLs are chosen by tenants, so they must not be able to use
+// them to reach services on our own network.
+fn is_public_address(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+                ip.is_broadcast() || ip.is_unspecified() ||
+                // "This network" and carrier-grade NAT
+                octets[0] == 0 || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() ||
+                // Unique local and link-local
+                (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80) &&
+                // IPv4 addresses embedded in IPv6 ones must be public too
+                ip.to_ipv4().map_or(true, |ip| is_public_address(&IpAddr::V4(ip)))
+        }
+    }
+}
+
+// Opens plain TCP connections, but only to public addresses. The check
+// is made against the addresses actually connected to, so it also
+// applies to redirects and to names which resolve to private addresses.
+#[derive(Debug, Clone, Copy)]
+struct PublicConnector {
+    allow_private: bool,
+}
+
+impl NetworkConnector for PublicConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _scheme: &str) -> hyper::Result<HttpStream> {
+        let host = host.trim_left_matches('[').trim_right_matches(']');
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host));
+        for addr in (host, port).to_socket_addrs()? {
+            if !self.allow_private && !is_public_address(&addr.ip()) {
+                last_error = io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not a public address", addr.ip()));
+                continue;
+            }
+            match TcpStream::connect(addr) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(e) => last_error = e
+            }
+        }
+        Err(last_error.into())
+    }
+}
+
+
+// Delivers webhook events from the outbox. Each webhook which has
+// not yet accepted an event is sent it, and if any of them fail the
+// error is returned so that the relay tries them again later.
+#[derive(Debug, Clone)]
+pub struct WebhookHandler {
+    timeout: Duration,
+    allow_private_addresses: bool,
+}
+
+impl Default for WebhookHandler {
+    fn default() -> Self {
+        WebhookHandler {
+            timeout: Duration::from_secs(10),
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl WebhookHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Only for tests and development, where receivers run locally
+    pub fn allow_private_addresses(mut self, allow: bool) -> Self {
+        self.allow_private_addresses = allow;
+        self
+    }
+
+    fn post(&self, webhook: &Webhook, event_id: &str, event: WebhookEvent, body: &str) -> (Option<i32>, Option<String>) {
+        let timestamp = Utc::now().timestamp();
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw("X-Checkout-Event", vec![event.as_str().as_bytes().to_vec()]);
+        headers.set_raw("X-Checkout-Event-Id", vec![event_id.as_bytes().to_vec()]);
+        headers.set_raw("X-Checkout-Timestamp", vec![timestamp.to_string().into_bytes()]);
+        headers.set_raw("X-Checkout-Signature", vec![sign(&webhook.secret, timestamp, body).into_bytes()]);
+
+        let connector = HttpsConnector::with_connector(TlsClient::new(), PublicConnector {
+            allow_private: self.allow_private_addresses,
+        });
+        let mut client = Client::with_connector(connector);
+        client.set_read_timeout(Some(self.timeout));
+        client.set_write_timeout(Some(self.timeout));
+        let mut response = match client.post(&webhook.url).headers(headers).body(body).send() {
+            Ok(response) => response,
+            Err(e) => return (None, Some(format!("Request failed: {}", e)))
+        };
+        let status = response.status.to_u16() as i32;
+        if response.status.is_success() {
+            (Some(status), None)
+        } else {
+            let mut text = String::new();
+            let _ = response.read_to_string(&mut text);
+            (Some(status), Some(format!("Webhook replied {}: {}", response.status, text.trim())))
+        }
+    }
+}
+
+impl OutboxHandler for WebhookHandler {
+    fn handle(&self, db: &Database, message: &OutboxMessage) -> Result<(), String> {
+        let envelope: EventEnvelope = serde_json::from_value(message.payload.clone())
+            .map_err(|e| e.to_string())?;
+        let body = serde_json::to_string(&EventPayload {
+            id: &message.idempotency_key,
+            event: envelope.event,
+            tenant_id: &envelope.tenant_id,
+            basket_id: envelope.basket_id,
+            occurred_at: envelope.occurred_at,
+            data: &envelope.data,
+        }).map_err(|e| e.to_string())?;
+
+        let mut failures = Vec::new();
+        for webhook in db.get_webhooks(&envelope.tenant_id).map_err(|e| e.to_string())? {
+            // Webhooks registered after the event do not receive it
+            if !webhook.is_subscribed(envelope.event) || webhook.created_at > message.created_at {
+                continue;
+            }
+            if db.has_webhook_succeeded(webhook.id, &message.idempotency_key).map_err(|e| e.to_string())? {
+                continue;
+            }
+            let attempted_at = Utc::now();
+            let (response_status, error) = self.post(&webhook, &message.idempotency_key, envelope.event, &body);
+            if let Some(ref error) = error {
+                failures.push(format!("{}: {}", webhook.url, error));
+            }
+            db.insert_webhook_delivery(&WebhookDelivery {
+                id: Uuid::new_v4(),
+                webhook_id: webhook.id,
+                event_id: message.idempotency_key.clone(),
+                event: envelope.event.as_str().into(),
+                attempted_at,
+                response_status,
+                error,
+            }).map_err(|e| e.to_string())?;
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; "))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        assert_eq!(
+            hmac_sha256_hex("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert_eq!(
+            sign("0123456789abcdef", 1500000000, r#"{"id":"1"}"#),
+            "sha256=c4dc3c21de293c63b0a46e055b995ce4f065580f0b412c417c721f64d25a7e12"
+        );
+    }
+
+    #[test]
+    fn event_names() {
+        for &event in &[
+            WebhookEvent::BasketCreated, WebhookEvent::ProfileAdded, WebhookEvent::RecipientSelected,
+            WebhookEvent::CheckoutSubmitted, WebhookEvent::CommunicationSent
+        ] {
+            assert_eq!(event.as_str().parse::<WebhookEvent>(), Ok(event));
+            assert_eq!(serde_json::to_value(event).unwrap(), json!(event.as_str()));
+        }
+        assert!("basket.deleted".parse::<WebhookEvent>().is_err());
+    }
+
+    #[test]
+    fn webhook_validation() {
+        let events = [WebhookEvent::ProfileAdded, WebhookEvent::BasketCreated, WebhookEvent::ProfileAdded];
+        let webhook = Webhook::new(" acme ", "https://hooks.example.com/checkout", "0123456789abcdef", &events).unwrap();
+        assert_eq!(webhook.tenant_id, "acme");
+        assert_eq!(webhook.events, vec!["basket.created", "profile.added"]);
+        assert!(webhook.is_subscribed(WebhookEvent::BasketCreated));
+        assert!(!webhook.is_subscribed(WebhookEvent::CommunicationSent));
+
+        let field = |result: Result<Webhook, ApiError>| result.unwrap_err().field.unwrap();
+        assert_eq!(field(Webhook::new("", "https://example.com", "0123456789abcdef", &events)), "tenantId");
+        assert_eq!(field(Webhook::new("acme", "ftp://example.com", "0123456789abcdef", &events)), "url");
+        assert_eq!(field(Webhook::new("acme", "http://example.com", "0123456789abcdef", &events)), "url");
+        assert_eq!(field(Webhook::new("acme", "/checkout", "0123456789abcdef", &events)), "url");
+        assert_eq!(field(Webhook::new("acme", "https://example.com", "short", &events)), "secret");
+        assert_eq!(field(Webhook::new("acme", "https://example.com", "0123456789abcdef", &[])), "events");
+    }
+
+    #[test]
+    fn public_addresses() {
+        for address in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_address(&address.parse().unwrap()), "{}", address);
+        }
+        for address in &[
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "255.255.255.255", "100.64.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:10.0.0.1"
+        ] {
+            assert!(!is_public_address(&address.parse().unwrap()), "{}", address);
+        }
+    }
+
+    #[test]
+    fn private_targets_are_refused() {
+        let webhook = Webhook::new("acme", "https://127.0.0.1:1/hooks", "0123456789abcdef", &[WebhookEvent::BasketCreated]).unwrap();
+        let (status, error) = WebhookHandler::new().post(&webhook, "1", WebhookEvent::BasketCreated, "{}");
+        assert_eq!(status, None);
+        assert!(error.unwrap().contains("127.0.0.1 is not a public address"));
+    }
+}
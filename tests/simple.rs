@@ -1,30 +1,39 @@
 extern crate checkout;
 extern crate iron_test;
 extern crate iron;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate uuid;
+extern crate chrono;
 
 use iron_test::request;
 use iron_test::response::extract_body_to_string;
 use iron::{Headers, Handler};
 use iron::status::Status;
+use std::{env, fs, thread};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
-use checkout::{Database, create_app, schema};
+use checkout::{ApiError, ErrorCode, Database, DatabaseError, DatabaseResult, Dispatcher, Outbox, Relay, WebhookHandler, COMMUNICATION_QUEUED, WEBHOOK_EVENT, create_app, schema, sign_webhook};
+use checkout::memory::MemoryDatabase;
+use checkout::smtp::SmtpTransport;
+use checkout::file_sink::FileSinkTransport;
+use checkout::registry::{MessageTemplate, TemplateRegistry};
 
 #[derive(Debug)]
-struct MockDatabase;
-
-impl Database for MockDatabase {
-    fn update_basket_impl(&self, basket_id: Uuid, f: &mut FnMut(&mut schema::Basket)) -> schema::Basket {
-        let mut result = schema::Basket {
-            id: basket_id,
-            ..Default::default()
-        };
-        f(&mut result);
-        result
+struct UnavailableDatabase;
+
+impl Database for UnavailableDatabase {
+    fn get_basket(&self, _basket_id: Uuid) -> DatabaseResult<Option<schema::Basket>> {
+        Err(DatabaseError::PoolTimeout)
+    }
+    fn update_basket_impl(&self, _basket_id: Uuid, _f: &mut FnMut(&mut schema::Basket, &mut Outbox) -> bool) -> DatabaseResult<schema::Basket> {
+        Err(DatabaseError::PoolTimeout)
     }
 }
 
@@ -36,35 +45,65 @@ fn get<H: Handler>(url: &str, app: &H) -> (Status, String) {
     )
 }
 
-fn post<H: Handler>(url: &str, app: &H, content: &str) -> (Status, String) {
-    let response = request::post(&format!("http://localhost:3000{}", url), Headers::new(), content, app).unwrap();
+fn post<H: Handler>(url: &str, app: &H, headers: Headers, content: &str) -> (Status, String) {
+    let response = request::post(&format!("http://localhost:3000{}", url), headers, content, app).unwrap();
     (
         response.status.unwrap(),
         extract_body_to_string(response)
     )
 }
 
-fn test_query<H: Handler>(app: &H, query: &str, expected_response: &str) {
+fn run_query<H: Handler>(app: &H, query: &str) -> (Status, serde_json::Value) {
+    run_query_as(app, None, query)
+}
+
+// Run a query as if the gateway had authenticated the caller as a tenant
+fn run_query_as<H: Handler>(app: &H, tenant_id: Option<&str>, query: &str) -> (Status, serde_json::Value) {
     #[derive(Serialize)]
     struct GraphQlRequest<'a> {
         query: &'a str
     }
 
-    let (code, response) = post("/graphql", app, &serde_json::to_string(&GraphQlRequest {
+    let mut headers = Headers::new();
+    if let Some(tenant_id) = tenant_id {
+        headers.set_raw("X-Tenant-Id", vec![tenant_id.as_bytes().to_vec()]);
+    }
+    let (code, response) = post("/graphql", app, headers, &serde_json::to_string(&GraphQlRequest {
         query
     }).unwrap());
 
+    (code, serde_json::from_str::<serde_json::Value>(&response).unwrap())
+}
+
+fn test_query<H: Handler>(app: &H, query: &str, expected_response: &str) {
+    test_query_as(app, None, query, expected_response)
+}
+
+fn test_query_as<H: Handler>(app: &H, tenant_id: Option<&str>, query: &str, expected_response: &str) {
+    let (code, response_value) = run_query_as(app, tenant_id, query);
+
     assert_eq!(code, Status::Ok);
 
-    let response_value = serde_json::from_str::<serde_json::Value>(&response).unwrap();
     let expected_value = serde_json::from_str::<serde_json::Value>(expected_response).unwrap();
     assert_eq!(response_value, expected_value);
 }
 
+fn test_query_error<H: Handler>(app: &H, query: &str, expected_code: &str) {
+    test_query_error_as(app, None, query, expected_code)
+}
+
+fn test_query_error_as<H: Handler>(app: &H, tenant_id: Option<&str>, query: &str, expected_code: &str) {
+    let (_, response_value) = run_query_as(app, tenant_id, query);
+
+    let message = response_value["errors"][0]["message"].as_str()
+        .expect("Expected an error response");
+    assert!(message.starts_with(expected_code), "Unexpected error: {}", message);
+}
+
 #[test]
 fn graphiql_test() {
     // Verify that we return the GraphiQL interface
-    let app = create_app(MockDatabase);
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
     let (code, response) = get("/", &app);
     assert_eq!(code, Status::Ok);
     assert!(response.trim_left().starts_with("<!DOCTYPE html>"));
@@ -73,7 +112,104 @@ fn graphiql_test() {
 #[test]
 fn smoke_test() {
     // Verify that we can run a query
-    let app = create_app(MockDatabase);
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query(&app,
+        r#"{
+            basket(id: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d") {
+                id
+            }
+        }"#,
+        r#"{
+            "data": {
+                "basket": null
+            }
+        }"#
+    );
+}
+
+#[test]
+fn create_basket_test() {
+    // Verify that baskets can be explicitly created
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let (code, response) = run_query(&app,
+        r#"mutation {
+            createBasket {
+                id
+                profilesToCheck {
+                    id
+                }
+            }
+        }"#
+    );
+    assert_eq!(code, Status::Ok);
+    let basket = &response["data"]["createBasket"];
+    let basket_id = basket["id"].as_str().unwrap();
+    assert!(basket_id.parse::<Uuid>().is_ok());
+    assert_eq!(basket["profilesToCheck"], serde_json::Value::Array(Vec::new()));
+
+    // The new basket should now be visible to queries
+    test_query(&app,
+        &format!(r#"{{
+            basket(id: "{}") {{
+                id
+            }}
+        }}"#, basket_id),
+        &format!(r#"{{
+            "data": {{
+                "basket": {{
+                    "id": "{}"
+                }}
+            }}
+        }}"#, basket_id)
+    );
+}
+
+#[test]
+fn database_error_test() {
+    // Verify that database failures are reported as GraphQL errors
+    let app = create_app(UnavailableDatabase, Dispatcher::new());
+    test_query_error(&app,
+        r#"{
+            basket(id: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d") {
+                id
+            }
+        }"#,
+        "POOL_TIMEOUT"
+    );
+}
+
+#[test]
+fn missing_profile_test() {
+    // Verify that mutation failures carry an error code
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query_error(&app,
+        r#"mutation {
+            setRecipientOnProfile(
+                basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91"
+            ) {
+                id
+            }
+        }"#,
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn failed_mutation_rollback_test() {
+    // Verify that a failed mutation does not create the basket
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query_error(&app,
+        r#"mutation {
+            setRecipientOnProfile(
+                basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91"
+            ) {
+                id
+            }
+        }"#,
+        "NOT_FOUND"
+    );
     test_query(&app,
         r#"{
             basket(id: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d") {
@@ -82,10 +218,1765 @@ fn smoke_test() {
         }"#,
         r#"{
             "data": {
-                "basket": {
-                    "id": "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d"
+                "basket": null
+            }
+        }"#
+    );
+}
+
+#[test]
+fn version_conflict_test() {
+    // Verify that stale updates are rejected
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let (_, response) = run_query(&app,
+        r#"mutation {
+            createBasket {
+                id
+                version
+            }
+        }"#
+    );
+    let basket = &response["data"]["createBasket"];
+    assert_eq!(basket["version"].as_i64(), Some(1));
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            setRecipientOnProfile(
+                basketId: "{}",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+                expectedVersion: 0
+            ) {{
+                id
+            }}
+        }}"#, basket["id"].as_str().unwrap()),
+        "CONFLICT"
+    );
+}
+
+#[test]
+fn manage_profiles_test() {
+    // Verify that profiles persist across mutations
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    for profile_id in &["6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91", "0b8e5a8c-93a4-4f3e-8f0e-7d2a1c6b5e40"] {
+        let (code, _) = run_query(&app, &format!(r#"mutation {{
+            addProfile(basketId: "{}", profileId: "{}") {{
+                id
+            }}
+        }}"#, basket_id, profile_id));
+        assert_eq!(code, Status::Ok);
+    }
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addProfile(basketId: "{}", profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91") {{
+                id
+            }}
+        }}"#, basket_id),
+        "CONFLICT"
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            moveProfile(basketId: "{}", profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91", index: 1) {{
+                profilesToCheck {{
+                    id
+                }}
+            }}
+        }}"#, basket_id),
+        r#"{
+            "data": {
+                "moveProfile": {
+                    "profilesToCheck": [
+                        { "id": "0b8e5a8c-93a4-4f3e-8f0e-7d2a1c6b5e40" },
+                        { "id": "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91" }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            removeProfile(basketId: "{}", profileId: "0b8e5a8c-93a4-4f3e-8f0e-7d2a1c6b5e40") {{
+                profilesToCheck {{
+                    id
+                }}
+            }}
+        }}"#, basket_id),
+        r#"{
+            "data": {
+                "removeProfile": {
+                    "profilesToCheck": [
+                        { "id": "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91" }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn manage_checks_test() {
+    // Verify that checks can be added and removed from a profile
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: COMPANY_REGISTRY) {{
+                id
+            }}
+        }}"#, basket_id, profile_id),
+        "INVALID_INPUT"
+    );
+
+    let (code, response) = run_query(&app, &format!(r#"mutation {{
+        addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+            profilesToCheck {{
+                needsInformation
+                checks {{
+                    id
+                    check
+                }}
+            }}
+        }}
+    }}"#, basket_id, profile_id));
+    assert_eq!(code, Status::Ok);
+    let profile = &response["data"]["addCheck"]["profilesToCheck"][0];
+    assert_eq!(profile["needsInformation"].as_bool(), Some(true));
+    assert_eq!(profile["checks"][0]["check"].as_str(), Some("IDENTITY_CHECK"));
+    let check_id = profile["checks"][0]["id"].as_str().unwrap();
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            removeCheck(basketId: "{}", profileId: "{}", checkId: "{}") {{
+                profilesToCheck {{
+                    needsInformation
+                    checks {{
+                        id
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id, check_id),
+        r#"{
+            "data": {
+                "removeCheck": {
+                    "profilesToCheck": [
+                        {
+                            "needsInformation": false,
+                            "checks": []
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn available_checks_test() {
+    // Verify that the front-end can discover which checks apply to a task
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query(&app,
+        r#"{
+            availableChecks(task: COMPANY_REVIEW_FILINGS)
+        }"#,
+        r#"{
+            "data": {
+                "availableChecks": ["COMPANY_FILINGS", "COMPANY_FILING_PURCHASE"]
+            }
+        }"#
+    );
+}
+
+#[test]
+fn collection_steps_test() {
+    // Verify that the calculated collection steps are exposed
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+                profilesToCheck {{
+                    collectionSteps {{
+                        kind
+                        ... on DobStep {{
+                            precision
+                        }}
+                        ... on AddressHistoryStep {{
+                            months
+                        }}
+                    }}
+                    extraCollectionSteps {{
+                        kind
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "addCheck": {
+                    "profilesToCheck": [
+                        {
+                            "collectionSteps": [
+                                { "kind": "FULL_NAME" },
+                                { "kind": "DOB", "precision": "YEAR_MONTH_DAY" },
+                                { "kind": "ADDRESS_HISTORY", "months": 0 }
+                            ],
+                            "extraCollectionSteps": []
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn extra_collection_steps_test() {
+    // Verify that operators can request extra information
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+    run_query(&app, &format!(r#"mutation {{
+        addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addExtraCollectionStep(basketId: "{}", profileId: "{}", step: {{ kind: ADDRESS_HISTORY }}) {{
+                id
+            }}
+        }}"#, basket_id, profile_id),
+        "INVALID_INPUT"
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            addExtraCollectionStep(basketId: "{}", profileId: "{}", step: {{ kind: ADDRESS_HISTORY, months: 36 }}) {{
+                profilesToCheck {{
+                    collectionSteps {{
+                        kind
+                        ... on AddressHistoryStep {{
+                            months
+                        }}
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "addExtraCollectionStep": {
+                    "profilesToCheck": [
+                        {
+                            "collectionSteps": [
+                                { "kind": "FULL_NAME" },
+                                { "kind": "DOB" },
+                                { "kind": "ADDRESS_HISTORY", "months": 36 }
+                            ]
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            removeExtraCollectionStep(basketId: "{}", profileId: "{}", step: {{ kind: ADDRESS_HISTORY }}) {{
+                profilesToCheck {{
+                    collectionSteps {{
+                        ... on AddressHistoryStep {{
+                            months
+                        }}
+                    }}
+                    extraCollectionSteps {{
+                        kind
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "removeExtraCollectionStep": {
+                    "profilesToCheck": [
+                        {
+                            "collectionSteps": [
+                                {},
+                                {},
+                                { "months": 0 }
+                            ],
+                            "extraCollectionSteps": []
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn submit_profile_data_test() {
+    // Verify that submitting data satisfies the collection steps
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+    run_query(&app, &format!(r#"mutation {{
+        addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            submitProfileData(basketId: "{}", profileId: "{}", data: {{
+                personalDetails: {{
+                    name: {{ givenNames: ["Ada"], familyName: "Lovelace" }},
+                    dob: "1815-12-10"
+                }},
+                addressHistory: [
+                    {{
+                        startDate: "2001-01",
+                        address: {{
+                            freeform: {{ country: "GBR", text: "12 St James's Square, London SW1Y 4JH" }}
+                        }}
+                    }}
+                ]
+            }}) {{
+                profilesToCheck {{
+                    needsInformation
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "submitProfileData": {
+                    "profilesToCheck": [
+                        { "needsInformation": false }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn outstanding_steps_test() {
+    // Verify that partially submitted data is reported
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+    run_query(&app, &format!(r#"mutation {{
+        addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            submitProfileData(basketId: "{}", profileId: "{}", data: {{
+                personalDetails: {{
+                    name: {{ givenNames: ["Ada"], familyName: "Lovelace" }},
+                    dob: "1815"
+                }}
+            }}) {{
+                profilesToCheck {{
+                    needsInformation
+                    outstandingSteps {{
+                        step {{
+                            kind
+                        }}
+                        status
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "submitProfileData": {
+                    "profilesToCheck": [
+                        {
+                            "needsInformation": true,
+                            "outstandingSteps": [
+                                { "step": { "kind": "DOB" }, "status": "PARTIAL" },
+                                { "step": { "kind": "ADDRESS_HISTORY" }, "status": "MISSING" }
+                            ]
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn invalid_date_test() {
+    // Verify that malformed dates are rejected on input
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let (code, response) = run_query(&app,
+        r#"mutation {
+            submitProfileData(
+                basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+                data: { personalDetails: { dob: "banana" } }
+            ) {
+                id
+            }
+        }"#
+    );
+    assert!(code != Status::Ok);
+    assert!(response["errors"].as_array().map_or(false, |errors| !errors.is_empty()));
+}
+
+#[test]
+fn address_history_findings_test() {
+    // Verify that gaps and overlaps in an address history are reported
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    run_query(&app, r#"mutation {
+        addProfile(basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d", profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91") {
+            id
+        }
+    }"#);
+    test_query(&app,
+        r#"mutation {
+            submitProfileData(
+                basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+                data: { addressHistory: [
+                    {
+                        startDate: "2014-01", endDate: "2016-06",
+                        address: { freeform: { country: "GBR", text: "1 High Street" } }
+                    },
+                    {
+                        startDate: "2013-01", endDate: "2014-03",
+                        address: { freeform: { country: "GBR", text: "2 High Street" } }
+                    },
+                    {
+                        startDate: "2016-10", endDate: "2017-01",
+                        address: { freeform: { country: "GBR", text: "3 High Street" } }
+                    }
+                ] }
+            ) {
+                profilesToCheck {
+                    addressHistory {
+                        monthsCovered
+                        continuousMonths
+                        findings {
+                            kind
+                            addressIndex
+                            otherAddressIndex
+                            from
+                            to
+                            months
+                        }
+                    }
+                }
+            }
+        }"#,
+        r#"{
+            "data": {
+                "submitProfileData": {
+                    "profilesToCheck": [
+                        {
+                            "addressHistory": {
+                                "monthsCovered": 46,
+                                "continuousMonths": 0,
+                                "findings": [
+                                    {
+                                        "kind": "MISSING_CURRENT_ADDRESS",
+                                        "addressIndex": null,
+                                        "otherAddressIndex": null,
+                                        "from": null,
+                                        "to": null,
+                                        "months": null
+                                    },
+                                    {
+                                        "kind": "OVERLAP",
+                                        "addressIndex": 2,
+                                        "otherAddressIndex": 1,
+                                        "from": null,
+                                        "to": null,
+                                        "months": 3
+                                    },
+                                    {
+                                        "kind": "GAP",
+                                        "addressIndex": null,
+                                        "otherAddressIndex": null,
+                                        "from": "2016-07",
+                                        "to": "2016-09",
+                                        "months": 3
+                                    }
+                                ]
+                            }
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn normalize_address_test() {
+    // Verify that addresses are validated and freeform addresses structured
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query(&app,
+        r#"{
+            structured: normalizeAddress(address: {
+                structured: { country: "nl", postalCode: "1012js", route: "Dam", streetNumber: "1" }
+            }) {
+                country
+                postalCode
+                route
+            }
+            freeform: normalizeAddress(address: {
+                freeform: { country: "GB", text: "10 Downing Street, London SW1A 2AA" }
+            }) {
+                country
+                postalCode
+                streetNumber
+                route
+                postalTown
+            }
+        }"#,
+        r#"{
+            "data": {
+                "structured": {
+                    "country": "NLD",
+                    "postalCode": "1012 JS",
+                    "route": "Dam"
+                },
+                "freeform": {
+                    "country": "GBR",
+                    "postalCode": "SW1A 2AA",
+                    "streetNumber": "10",
+                    "route": "Downing Street",
+                    "postalTown": "London"
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn invalid_address_test() {
+    // Verify that invalid addresses are reported against the field at fault
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    test_query_error(&app,
+        r#"mutation {
+            submitProfileData(
+                basketId: "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d",
+                profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91",
+                data: { addressHistory: [{
+                    address: { structured: { country: "US", postalCode: "SW1A 2AA" } }
+                }] }
+            ) {
+                id
+            }
+        }"#,
+        "INVALID_INPUT: data.addressHistory[0].address.structured.postalCode:"
+    );
+    test_query_error(&app,
+        r#"{
+            normalizeAddress(address: { freeform: { country: "Atlantis", text: "1 Main Street" } }) {
+                country
+            }
+        }"#,
+        "INVALID_INPUT: address.freeform.country:"
+    );
+}
+
+#[test]
+fn submit_company_data_test() {
+    // Verify that company data is stored and satisfies company steps
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}", entityType: COMPANY) {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+    run_query(&app, &format!(r#"mutation {{
+        addCheck(basketId: "{}", profileId: "{}", task: COMPANY_VERIFY_IDENTITY, check: COMPANY_REGISTRY) {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            submitCompanyData(basketId: "{}", profileId: "{}", data: {{
+                legalName: "Acme Widgets Ltd",
+                registrationNumber: "01234567",
+                registeredAddress: {{
+                    structured: {{ country: "GB", postalCode: "ec1a1bb", route: "King Edward Street" }}
+                }},
+                incorporationDate: "1999-03",
+                officers: [{{ name: "Wile E. Coyote", role: DIRECTOR, appointedOn: "1999-03-01" }}],
+                beneficialOwners: [{{ name: "Road Runner Holdings", entityType: COMPANY, ownershipPercentage: 75.5 }}],
+                filings: [{{ documentType: CONFIRMATION_STATEMENT, filedOn: "2017-03-14" }}]
+            }}) {{
+                profilesToCheck {{
+                    companyData {{
+                        legalName
+                        registeredAddress {{
+                            country
+                            ... on StructuredAddress {{
+                                postalCode
+                            }}
+                        }}
+                        incorporationDate
+                        officers {{ name role appointedOn }}
+                        beneficialOwners {{ name entityType ownershipPercentage }}
+                        filings {{ documentType filedOn }}
+                    }}
+                    outstandingSteps {{
+                        step {{
+                            kind
+                        }}
+                        status
+                    }}
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "submitCompanyData": {
+                    "profilesToCheck": [
+                        {
+                            "companyData": {
+                                "legalName": "Acme Widgets Ltd",
+                                "registeredAddress": {
+                                    "country": "GBR",
+                                    "postalCode": "EC1A 1BB"
+                                },
+                                "incorporationDate": "1999-03",
+                                "officers": [
+                                    { "name": "Wile E. Coyote", "role": "DIRECTOR", "appointedOn": "1999-03-01" }
+                                ],
+                                "beneficialOwners": [
+                                    { "name": "Road Runner Holdings", "entityType": "COMPANY", "ownershipPercentage": 75.5 }
+                                ],
+                                "filings": [
+                                    { "documentType": "CONFIRMATION_STATEMENT", "filedOn": "2017-03-14" }
+                                ]
+                            },
+                            "outstandingSteps": [
+                                { "step": { "kind": "JURISDICTION" }, "status": "MISSING" }
+                            ]
+                        }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            submitCompanyData(basketId: "{}", profileId: "{}", data: {{
+                beneficialOwners: [
+                    {{ name: "Road Runner Holdings", entityType: COMPANY, ownershipPercentage: 75.5 }},
+                    {{ name: "Wile E. Coyote", entityType: INDIVIDUAL, ownershipPercentage: 50.0 }}
+                ]
+            }}) {{
+                id
+            }}
+        }}"#, basket_id, profile_id),
+        "INVALID_INPUT: data.beneficialOwners:"
+    );
+}
+
+#[test]
+fn profile_entity_type_test() {
+    // Verify that checks and data must match the profile's entity type
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    test_query(&app,
+        &format!(r#"mutation {{
+            addProfile(basketId: "{}", profileId: "{}", entityType: COMPANY) {{
+                profilesToCheck {{
+                    entityType
+                }}
+            }}
+        }}"#, basket_id, profile_id),
+        r#"{
+            "data": {
+                "addProfile": {
+                    "profilesToCheck": [
+                        { "entityType": "COMPANY" }
+                    ]
+                }
+            }
+        }"#
+    );
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addCheck(basketId: "{}", profileId: "{}", task: INDIVIDUAL_VERIFY_IDENTITY, check: IDENTITY_CHECK) {{
+                id
+            }}
+        }}"#, basket_id, profile_id),
+        "INVALID_INPUT"
+    );
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            submitProfileData(basketId: "{}", profileId: "{}", data: {{}}) {{
+                id
+            }}
+        }}"#, basket_id, profile_id),
+        "INVALID_INPUT"
+    );
+}
+
+#[test]
+fn manage_recipients_test() {
+    // Verify that recipients can be added, updated and removed
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let recipient_id = "3c9a7f12-5b8e-4d61-a0f4-9e2b7c1d8a35";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91", possibleRecipients: ["{}"]) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            updateRecipient(basketId: "{}", recipientId: "{}", name: "Ada Lovelace", contactMethod: {{ phoneNumber: "+447700900123" }}) {{
+                recipients {{
+                    name
+                    ... on SmsRecipient {{
+                        phoneNumber
+                    }}
+                }}
+            }}
+        }}"#, basket_id, recipient_id),
+        r#"{
+            "data": {
+                "updateRecipient": {
+                    "recipients": [
+                        { "name": "Ada Lovelace", "phoneNumber": "+447700900123" }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{}}) {{
+                id
+            }}
+        }}"#, basket_id, recipient_id),
+        "INVALID_INPUT: contactMethod:"
+    );
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            removeRecipient(basketId: "{}", recipientId: "{}") {{
+                id
+            }}
+        }}"#, basket_id, recipient_id),
+        "CONFLICT"
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            removeRecipient(basketId: "{}", recipientId: "{}", clearReferences: true) {{
+                recipients {{
+                    id
+                }}
+                profilesToCheck {{
+                    possibleRecipients
+                }}
+            }}
+        }}"#, basket_id, recipient_id),
+        r#"{
+            "data": {
+                "removeRecipient": {
+                    "recipients": [],
+                    "profilesToCheck": [
+                        { "possibleRecipients": [] }
+                    ]
+                }
+            }
+        }"#
+    );
+}
+
+#[test]
+fn dangling_reference_test() {
+    // Verify that mutations cannot leave references dangling
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let profile_id = "6f1d4e2a-0c3b-4d8e-9a57-2b1f0e6c7d91";
+    let recipient_id = "3c9a7f12-5b8e-4d61-a0f4-9e2b7c1d8a35";
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addProfile(basketId: "{}", profileId: "{}", possibleRecipients: ["{}"]) {{
+                id
+            }}
+        }}"#, basket_id, profile_id, recipient_id),
+        "INVALID_INPUT"
+    );
+
+    run_query(&app, &format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id));
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+
+    // The recipient exists, but is not a possible recipient for the profile
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            setRecipientOnProfile(basketId: "{}", profileId: "{}", recipientId: "{}") {{
+                id
+            }}
+        }}"#, basket_id, profile_id, recipient_id),
+        "INVALID_INPUT"
+    );
+}
+
+#[test]
+fn contact_method_validation_test() {
+    // Verify that contact details are validated and normalised
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "3c9a7f12-5b8e-4d61-a0f4-9e2b7c1d8a35", name: "Ada", contactMethod: {{ email: " ada@Example.COM " }}) {{
+            id
+        }}
+    }}"#, basket_id));
+    test_query(&app,
+        &format!(r#"mutation {{
+            addRecipient(basketId: "{}", recipientId: "9d4b2e61-7f3a-4c85-b1e0-6a8f5d2c3b17", name: "Charles", contactMethod: {{ phoneNumber: "0044 7700 900-123" }}) {{
+                recipients {{
+                    ... on EmailRecipient {{
+                        address
+                    }}
+                    ... on SmsRecipient {{
+                        phoneNumber
+                        nationalFormat
+                    }}
+                }}
+            }}
+        }}"#, basket_id),
+        r#"{
+            "data": {
+                "addRecipient": {
+                    "recipients": [
+                        { "address": "ada@example.com" },
+                        { "phoneNumber": "+447700900123", "nationalFormat": "07700900123" }
+                    ]
                 }
             }
         }"#
     );
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addRecipient(basketId: "{}", recipientId: "0e7c3a94-2d5b-4f18-8b6a-1c9e4d7f2a50", name: "Ada", contactMethod: {{ email: "ada@localhost" }}) {{
+                id
+            }}
+        }}"#, basket_id),
+        "INVALID_INPUT: contactMethod.email:"
+    );
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            addRecipient(basketId: "{}", recipientId: "0e7c3a94-2d5b-4f18-8b6a-1c9e4d7f2a50", name: "Ada", contactMethod: {{ phoneNumber: "07700 900123" }}) {{
+                id
+            }}
+        }}"#, basket_id),
+        "INVALID_INPUT: contactMethod.phoneNumber:"
+    );
+}
+
+#[test]
+fn communication_lifecycle_test() {
+    // Verify that communications can be drafted, edited, queued and cancelled
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new());
+    let basket_id = "fcf7269c-2ecc-45b8-8573-c79bb3e10e8d";
+    let recipient_id = "3c9a7f12-5b8e-4d61-a0f4-9e2b7c1d8a35";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE, publicArgs: {{ from: "Acme" }}) {{
+            communications {{
+                id
+                kind
+                status
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication = &response["data"]["createCommunication"]["communications"][0];
+    assert_eq!(communication["kind"], "INVITE");
+    assert_eq!(communication["status"], "DRAFT");
+    let communication_id = communication["id"].as_str().unwrap();
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            updateCommunicationPublicArgs(basketId: "{}", communicationId: "{}", publicArgs: {{ from: "Acme Ltd", bcc: ["Audit@Example.com"] }}) {{
+                communications {{
+                    publicArgs {{
+                        from
+                        bcc
+                    }}
+                }}
+            }}
+        }}"#, basket_id, communication_id),
+        r#"{
+            "data": {
+                "updateCommunicationPublicArgs": {
+                    "communications": [
+                        { "publicArgs": { "from": "Acme Ltd", "bcc": ["Audit@example.com"] } }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            cancelCommunication(basketId: "{}", communicationId: "{}") {{
+                communications {{
+                    status
+                }}
+            }}
+        }}"#, basket_id, communication_id),
+        r#"{
+            "data": {
+                "cancelCommunication": {
+                    "communications": [
+                        { "status": "CANCELLED" }
+                    ]
+                }
+            }
+        }"#
+    );
+
+    // Cancelled communications can no longer be changed
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            updateCommunicationPublicArgs(basketId: "{}", communicationId: "{}", publicArgs: {{ from: "Acme" }}) {{
+                id
+            }}
+        }}"#, basket_id, communication_id),
+        "CONFLICT"
+    );
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            queueCommunication(basketId: "{}", communicationId: "{}") {{
+                id
+            }}
+        }}"#, basket_id, communication_id),
+        "CONFLICT"
+    );
+
+    // Communications must be addressed to a known recipient
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            createCommunication(basketId: "{}", recipientId: "0e7c3a94-2d5b-4f18-8b6a-1c9e4d7f2a50", kind: REMINDER) {{
+                id
+            }}
+        }}"#, basket_id),
+        "INVALID_INPUT"
+    );
+}
+
+#[test]
+fn send_email_test() {
+    // Verify that emails are sent to the recipient and any bcc addresses
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        // Just enough of SMTP to accept one message
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut recipients = Vec::new();
+        let mut data = String::new();
+        let mut in_data = false;
+        writer.write_all(b"220 localhost\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    writer.write_all(b"250 Queued\r\n").unwrap();
+                } else {
+                    data.push_str(&line);
+                }
+            } else if line.starts_with("RCPT TO:") {
+                recipients.push(line["RCPT TO:".len()..].trim().to_string());
+                writer.write_all(b"250 OK\r\n").unwrap();
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                writer.write_all(b"354 Go ahead\r\n").unwrap();
+            } else if line.starts_with("QUIT") {
+                writer.write_all(b"221 Bye\r\n").unwrap();
+                break;
+            } else {
+                writer.write_all(b"250 OK\r\n").unwrap();
+            }
+        }
+        (recipients, data)
+    });
+
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new()
+        .email_transport(SmtpTransport::new(address.to_string(), "noreply@example.com")));
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone()).handler(COMMUNICATION_QUEUED, dispatcher);
+    let basket_id = "5d0c1b2e-7a43-4f9e-b8c6-2e1f0a9d3c74";
+    let recipient_id = "a81f4c0e-3b2d-4e6a-9c57-0d8e1f2b3a46";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE, publicArgs: {{ from: "Acme", bcc: ["audit@example.com"] }}) {{
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication_id = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().to_string();
+
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        sendCommunication(basketId: "{}", communicationId: "{}") {{
+            communications {{
+                status
+            }}
+        }}
+    }}"#, basket_id, communication_id));
+    assert_eq!(response["data"]["sendCommunication"]["communications"][0]["status"], "QUEUED");
+
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+                deliveryAttempts {{
+                    transport
+                    error
+                }}
+            }}
+        }}
+    }}"#, basket_id));
+    assert_eq!(response["data"]["basket"]["communications"][0], json!({
+        "status": "SENT",
+        "deliveryAttempts": [
+            { "transport": "smtp", "error": null }
+        ]
+    }));
+
+    let (recipients, data) = server.join().unwrap();
+    assert_eq!(recipients, vec!["<ada@example.com>", "<audit@example.com>"]);
+    assert!(data.contains("From: \"Acme\" <noreply@example.com>\r\n"));
+    assert!(data.contains("Subject: Acme has asked you for some information\r\n"));
+    assert!(data.contains("Hello Ada,"));
+    assert!(!data.contains("audit@example.com"));
+
+    // Sent communications cannot be sent again
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            sendCommunication(basketId: "{}", communicationId: "{}") {{
+                id
+            }}
+        }}"#, basket_id, communication_id),
+        "CONFLICT"
+    );
+}
+
+#[test]
+fn send_sms_test() {
+    // Verify that SMS messages are handed to the SMS transport
+    let path = env::temp_dir().join(format!("checkout-sms-{}.jsonl", Uuid::new_v4()));
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new()
+        .sms_transport(FileSinkTransport::new(path.clone()))
+        .default_sender("Acme"));
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone()).handler(COMMUNICATION_QUEUED, dispatcher);
+    let basket_id = "9e3b7d21-4c6f-4a08-b5e2-7f1d0c8a9b63";
+    let recipient_id = "0b6d2f8a-1e4c-4973-8a5d-c3e7f9b1d024";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ phoneNumber: "+44 7700 900123" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: REMINDER) {{
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication_id = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().to_string();
+
+    run_query(&app, &format!(r#"mutation {{
+        sendCommunication(basketId: "{}", communicationId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, communication_id));
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+            }}
+        }}
+    }}"#, basket_id));
+    assert_eq!(response["data"]["basket"]["communications"][0]["status"], "SENT");
+
+    let mut contents = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let message = serde_json::from_str::<serde_json::Value>(lines[0]).unwrap();
+    assert_eq!(message["communication_id"], communication_id);
+    assert_eq!(message["to"], "+447700900123");
+    assert_eq!(message["body"], "Hello Ada,\n\nAcme is still waiting for you to provide some information.\n");
+}
+
+#[test]
+fn send_without_transport_test() {
+    // Verify that failed deliveries are recorded, and can be retried
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new());
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone())
+        .handler(COMMUNICATION_QUEUED, dispatcher)
+        .retry_delay(Duration::from_secs(60), Duration::from_secs(60));
+    let basket_id = "2f8e4a6c-0d1b-4c3e-9a7f-5b6d8e0c1a92";
+    let recipient_id = "7c1e9b3d-5f2a-4d8c-b064-a3e5c7d9f1b8";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE) {{
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication_id = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().to_string();
+
+    let send_query = format!(r#"mutation {{
+        sendCommunication(basketId: "{}", communicationId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, communication_id);
+    let basket_query = format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+                deliveryAttempts {{
+                    transport
+                    error
+                }}
+            }}
+        }}
+    }}"#, basket_id);
+    run_query(&app, &send_query);
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let (_, response) = run_query(&app, &basket_query);
+    assert_eq!(response["data"]["basket"]["communications"][0], json!({
+        "status": "FAILED",
+        "deliveryAttempts": [
+            { "transport": null, "error": "No transport is configured for email" }
+        ]
+    }));
+
+    // Sending again queues another attempt straight away
+    run_query(&app, &send_query);
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let (_, response) = run_query(&app, &basket_query);
+    let communication = &response["data"]["basket"]["communications"][0];
+    assert_eq!(communication["status"], "FAILED");
+    assert_eq!(communication["deliveryAttempts"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn preview_communication_test() {
+    // Verify that communications are rendered with the template for their locale
+    let mut templates = TemplateRegistry::new();
+    templates.insert(schema::CommunicationKind::Invite, "fr", MessageTemplate {
+        subject: "{{sender}} vous demande des informations".parse().unwrap(),
+        body: "Bonjour {{recipient_name}}, panier {{basket_id}}".parse().unwrap(),
+    });
+    let app = create_app(MemoryDatabase::new(), Dispatcher::new().templates(templates));
+    let basket_id = "c4a9e1f7-3d2b-4b86-9e05-8f7a6d1c2b39";
+    let recipient_id = "e2d8f6a4-1c3b-4a97-b5e0-9d7c3f1a8b62";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE, publicArgs: {{ from: "Acme", locale: "fr_fr" }}) {{
+            communications {{
+                id
+                publicArgs {{
+                    locale
+                }}
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication = &response["data"]["createCommunication"]["communications"][0];
+    assert_eq!(communication["publicArgs"]["locale"], "fr-FR");
+    let communication_id = communication["id"].as_str().unwrap().to_string();
+
+    let query = format!(r#"{{
+        previewCommunication(basketId: "{}", communicationId: "{}") {{
+            to
+            subject
+            body
+            locale
+        }}
+    }}"#, basket_id, communication_id);
+    test_query(&app, &query, &format!(r#"{{
+        "data": {{
+            "previewCommunication": {{
+                "to": "ada@example.com",
+                "subject": "Acme vous demande des informations",
+                "body": "Bonjour Ada, panier {}",
+                "locale": "fr"
+            }}
+        }}
+    }}"#, basket_id));
+
+    // Locales without templates fall back to the built-in templates
+    run_query(&app, &format!(r#"mutation {{
+        updateCommunicationPublicArgs(basketId: "{}", communicationId: "{}", publicArgs: {{ from: "Acme", locale: "de" }}) {{
+            id
+        }}
+    }}"#, basket_id, communication_id));
+    let (_, response) = run_query(&app, &query);
+    assert_eq!(response["data"]["previewCommunication"]["subject"], "Acme has asked you for some information");
+    assert_eq!(response["data"]["previewCommunication"]["locale"], "en");
+
+    // Previewing does not send anything
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+            }}
+        }}
+    }}"#, basket_id));
+    assert_eq!(response["data"]["basket"]["communications"][0]["status"], "DRAFT");
+
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            updateCommunicationPublicArgs(basketId: "{}", communicationId: "{}", publicArgs: {{ locale: "french" }}) {{
+                id
+            }}
+        }}"#, basket_id, communication_id),
+        "INVALID_INPUT: publicArgs.locale"
+    );
+    test_query_error(&app,
+        &format!(r#"{{
+            previewCommunication(basketId: "{}", communicationId: "{}") {{
+                body
+            }}
+        }}"#, basket_id, recipient_id),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn queue_communication_test() {
+    // Verify that queued communications are sent by the outbox relay
+    let path = env::temp_dir().join(format!("checkout-sms-{}.jsonl", Uuid::new_v4()));
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new().sms_transport(FileSinkTransport::new(path.clone())));
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone()).handler(COMMUNICATION_QUEUED, dispatcher);
+    let basket_id = "3b5f7d9e-1a2c-4e6f-8b0d-2c4e6a8f0b1d";
+    let recipient_id = "8d0f2b4c-6e8a-4c1e-9f3b-5d7f9b1d3e5a";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ phoneNumber: "+44 7700 900123" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE) {{
+            version
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let version = response["data"]["createCommunication"]["version"].as_i64().unwrap();
+    let communication_id = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().to_string();
+
+    // Nothing is added to the outbox if the change is rejected
+    test_query_error(&app,
+        &format!(r#"mutation {{
+            queueCommunication(basketId: "{}", communicationId: "{}", expectedVersion: {}) {{
+                id
+            }}
+        }}"#, basket_id, communication_id, version - 1),
+        "CONFLICT"
+    );
+    assert!(db.outbox_messages().is_empty());
+
+    test_query(&app,
+        &format!(r#"mutation {{
+            queueCommunication(basketId: "{}", communicationId: "{}", expectedVersion: {}) {{
+                communications {{
+                    status
+                }}
+            }}
+        }}"#, basket_id, communication_id, version),
+        r#"{
+            "data": {
+                "queueCommunication": {
+                    "communications": [
+                        { "status": "QUEUED" }
+                    ]
+                }
+            }
+        }"#
+    );
+    assert_eq!(db.outbox_messages().len(), 1);
+
+    assert_eq!(relay.run_once().unwrap(), 1);
+    assert_eq!(relay.run_once().unwrap(), 0);
+    assert!(db.outbox_messages()[0].delivered_at.is_some());
+
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+            }}
+        }}
+    }}"#, basket_id));
+    assert_eq!(response["data"]["basket"]["communications"][0]["status"], "SENT");
+
+    let mut contents = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(contents.lines().count(), 1);
+}
+
+#[test]
+fn concurrent_dispatch_test() {
+    // Verify that a communication which is being sent is not sent again
+    let path = env::temp_dir().join(format!("checkout-sms-{}.jsonl", Uuid::new_v4()));
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new().sms_transport(FileSinkTransport::new(path.clone())));
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone()).handler(COMMUNICATION_QUEUED, dispatcher.clone());
+    let basket_id: Uuid = "5c7e9a1b-3d5f-4b7d-9e1a-4c6e8a0c2e4f".parse().unwrap();
+    let recipient_id = "a2c4e6a8-0b2d-4f6a-8c1e-3a5c7e9b1d3f";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ phoneNumber: "+44 7700 900123" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE) {{
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication_id: Uuid = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().parse().unwrap();
+    run_query(&app, &format!(r#"mutation {{
+        queueCommunication(basketId: "{}", communicationId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, communication_id));
+
+    // Claim the communication, as if another dispatcher were sending it
+    let now = chrono::Utc::now();
+    let db_ref: &Database = &*db;
+    db_ref.update_basket(basket_id, &mut |basket: &mut schema::Basket| {
+        basket.contents.0.find_communication_mut(communication_id)?.claim(now, now + chrono::Duration::minutes(1))
+    }).unwrap();
+
+    let error: ApiError = dispatcher.dispatch(db_ref, basket_id, communication_id).unwrap_err();
+    assert_eq!(error.code, ErrorCode::Conflict);
+
+    // The relay tries again later, rather than sending it as well
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let message = db.outbox_messages().pop().unwrap();
+    assert_eq!(message.attempts, 1);
+    assert!(message.delivered_at.is_none());
+    assert!(!path.exists());
+
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+                deliveryAttempts {{
+                    error
+                }}
+            }}
+        }}
+    }}"#, basket_id));
+    assert_eq!(response["data"]["basket"]["communications"][0], json!({
+        "status": "SENDING",
+        "deliveryAttempts": []
+    }));
+}
+
+#[test]
+fn retry_communication_test() {
+    // Verify that failed deliveries from the outbox are retried
+    let db = Arc::new(MemoryDatabase::new());
+    let dispatcher = Arc::new(Dispatcher::new());
+    let app = create_app(db.clone(), dispatcher.clone());
+    let relay = Relay::new(db.clone())
+        .handler(COMMUNICATION_QUEUED, dispatcher)
+        .retry_delay(Duration::from_secs(0), Duration::from_secs(0));
+    let basket_id = "6a8c0e2f-4b6d-4f8a-a1c3-7e9a1c3e5f70";
+    let recipient_id = "f1a3c5e7-9b1d-4d3f-8a5c-0e2a4c6e8a0c";
+    run_query(&app, &format!(r#"mutation {{
+        addRecipient(basketId: "{}", recipientId: "{}", name: "Ada", contactMethod: {{ email: "ada@example.com" }}) {{
+            id
+        }}
+    }}"#, basket_id, recipient_id));
+    let (_, response) = run_query(&app, &format!(r#"mutation {{
+        createCommunication(basketId: "{}", recipientId: "{}", kind: INVITE) {{
+            communications {{
+                id
+            }}
+        }}
+    }}"#, basket_id, recipient_id));
+    let communication_id = response["data"]["createCommunication"]["communications"][0]["id"].as_str().unwrap().to_string();
+    run_query(&app, &format!(r#"mutation {{
+        queueCommunication(basketId: "{}", communicationId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, communication_id));
+
+    assert_eq!(relay.run_once().unwrap(), 1);
+    assert_eq!(relay.run_once().unwrap(), 1);
+    let message = db.outbox_messages().pop().unwrap();
+    assert_eq!(message.attempts, 2);
+    assert_eq!(message.last_error.as_ref().map(|s| &**s), Some("No transport is configured for email"));
+
+    let (_, response) = run_query(&app, &format!(r#"{{
+        basket(id: "{}") {{
+            communications {{
+                status
+                deliveryAttempts {{
+                    error
+                }}
+            }}
+        }}
+    }}"#, basket_id));
+    let communication = &response["data"]["basket"]["communications"][0];
+    assert_eq!(communication["status"], "FAILED");
+    assert_eq!(communication["deliveryAttempts"].as_array().unwrap().len(), 2);
+
+    // Once cancelled, the message is no longer retried
+    run_query(&app, &format!(r#"mutation {{
+        cancelCommunication(basketId: "{}", communicationId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, communication_id));
+    assert_eq!(relay.run_once().unwrap(), 1);
+    assert!(db.outbox_messages()[0].delivered_at.is_some());
+}
+
+#[test]
+fn webhook_test() {
+    // Verify that a tenant's basket events are signed and posted to its webhooks
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        // Fail the first request, and accept the rest
+        let mut requests = Vec::new();
+        for i in 0..4 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut headers = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_right().to_string();
+                if line.is_empty() {
+                    break;
+                }
+                if line.to_lowercase().starts_with("content-length:") {
+                    content_length = line["content-length:".len()..].trim().parse().unwrap();
+                }
+                headers.push(line);
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let status = if i == 0 { "500 Internal Server Error" } else { "200 OK" };
+            write!(writer, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            requests.push((headers, String::from_utf8(body).unwrap()));
+        }
+        requests
+    });
+
+    let db = Arc::new(MemoryDatabase::new());
+    let app = create_app(db.clone(), Dispatcher::new());
+    let relay = Relay::new(db.clone())
+        .handler(WEBHOOK_EVENT, WebhookHandler::new().allow_private_addresses(true))
+        .retry_delay(Duration::from_secs(0), Duration::from_secs(0));
+    let acme = Some("acme");
+
+    // Only the tenant itself may manage its webhooks
+    let create_webhook = format!(r#"mutation {{
+        createWebhook(tenantId: "acme", url: "https://{}/hooks", secret: "0123456789abcdef", events: [BASKET_CREATED, PROFILE_ADDED, CHECKOUT_SUBMITTED]) {{
+            id
+            events
+        }}
+    }}"#, address);
+    test_query_error(&app, &create_webhook, "FORBIDDEN");
+    test_query_error_as(&app, Some("globex"), &create_webhook, "FORBIDDEN");
+    let (_, response) = run_query_as(&app, acme, &create_webhook);
+    let webhook = &response["data"]["createWebhook"];
+    assert_eq!(webhook["events"], json!(["BASKET_CREATED", "CHECKOUT_SUBMITTED", "PROFILE_ADDED"]));
+    let webhook_id = webhook["id"].as_str().unwrap().to_string();
+
+    // The test receiver does not speak TLS, so deliver to it over plain HTTP
+    let mut stored = db.delete_webhook(webhook_id.parse().unwrap()).unwrap().unwrap();
+    stored.url = format!("http://{}/hooks", address);
+    db.insert_webhook(&stored).unwrap();
+
+    test_query_error_as(&app, acme,
+        r#"mutation {
+            createWebhook(tenantId: "acme", url: "http://example.com", secret: "0123456789abcdef", events: [BASKET_CREATED]) {
+                id
+            }
+        }"#,
+        "INVALID_INPUT: url"
+    );
+    test_query_error_as(&app, acme,
+        r#"mutation {
+            createWebhook(tenantId: "acme", url: "https://example.com", secret: "secret", events: [BASKET_CREATED]) {
+                id
+            }
+        }"#,
+        "INVALID_INPUT: secret"
+    );
+
+    // Baskets without a tenant do not produce any events
+    run_query(&app, "mutation { createBasket { id } }");
+    assert!(db.outbox_messages().is_empty());
+
+    test_query_error(&app, r#"mutation { createBasket(tenantId: "acme") { id } }"#, "FORBIDDEN");
+    assert!(db.outbox_messages().is_empty());
+    let (_, response) = run_query_as(&app, acme, r#"mutation {
+        createBasket(tenantId: "acme") {
+            id
+            tenantId
+        }
+    }"#);
+    let basket = &response["data"]["createBasket"];
+    assert_eq!(basket["tenantId"], "acme");
+    let basket_id = basket["id"].as_str().unwrap().to_string();
+
+    test_query_error_as(&app, acme,
+        &format!(r#"mutation {{
+            submitCheckout(basketId: "{}") {{
+                id
+            }}
+        }}"#, basket_id),
+        "INVALID_INPUT"
+    );
+    // Only the tenant can change its baskets, and so trigger its webhooks
+    let add_profile = format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "5c3e1a9f-7b2d-4e8c-a6f0-1d3b5f7a9c2e") {{
+            id
+        }}
+    }}"#, basket_id);
+    test_query_error(&app, &add_profile, "FORBIDDEN");
+    test_query_error_as(&app, Some("globex"), &add_profile, "FORBIDDEN");
+    run_query_as(&app, acme, &add_profile);
+    let (_, response) = run_query_as(&app, acme, &format!(r#"mutation {{
+        submitCheckout(basketId: "{}") {{
+            submittedAt
+        }}
+    }}"#, basket_id));
+    assert!(response["data"]["submitCheckout"]["submittedAt"].is_string());
+    test_query_error_as(&app, acme,
+        &format!(r#"mutation {{
+            submitCheckout(basketId: "{}") {{
+                id
+            }}
+        }}"#, basket_id),
+        "CONFLICT"
+    );
+
+    // The first delivery fails, and is retried
+    assert_eq!(relay.run_once().unwrap(), 3);
+    assert_eq!(relay.run_once().unwrap(), 1);
+    assert_eq!(relay.run_once().unwrap(), 0);
+
+    let requests = server.join().unwrap();
+    let events: Vec<_> = requests.iter()
+        .map(|&(_, ref body)| serde_json::from_str::<serde_json::Value>(body).unwrap())
+        .collect();
+    assert_eq!(events[0]["event"], "basket.created");
+    assert_eq!(events[1]["event"], "profile.added");
+    assert_eq!(events[1]["data"]["profile_id"], "5c3e1a9f-7b2d-4e8c-a6f0-1d3b5f7a9c2e");
+    assert_eq!(events[2]["event"], "checkout.submitted");
+    assert_eq!(events[3]["event"], "basket.created");
+    // Retries are identified as the same event
+    assert_eq!(events[0]["id"], events[3]["id"]);
+    for event in &events {
+        assert_eq!(event["tenant_id"], "acme");
+        assert_eq!(event["basket_id"], basket_id);
+    }
+    for &(ref headers, ref body) in &requests {
+        let header = |name: &str| headers.iter()
+            .find(|h| h.starts_with(&format!("{}: ", name)))
+            .map(|h| h[name.len() + 2..].to_string())
+            .unwrap();
+        // The receiver can check the signature using the shared secret
+        let timestamp: i64 = header("X-Checkout-Timestamp").parse().unwrap();
+        assert_eq!(header("X-Checkout-Signature"), sign_webhook("0123456789abcdef", timestamp, body));
+        assert_ne!(header("X-Checkout-Signature"), sign_webhook("0123456789abcdeg", timestamp, body));
+    }
+
+    test_query_error_as(&app, Some("globex"), r#"{ webhooks(tenantId: "acme") { id } }"#, "FORBIDDEN");
+    test_query_as(&app, acme,
+        r#"{
+            webhooks(tenantId: "acme") {
+                deliveries {
+                    event
+                    responseStatus
+                    succeeded
+                }
+            }
+        }"#,
+        r#"{
+            "data": {
+                "webhooks": [{
+                    "deliveries": [
+                        { "event": "BASKET_CREATED", "responseStatus": 200, "succeeded": true },
+                        { "event": "CHECKOUT_SUBMITTED", "responseStatus": 200, "succeeded": true },
+                        { "event": "PROFILE_ADDED", "responseStatus": 200, "succeeded": true },
+                        { "event": "BASKET_CREATED", "responseStatus": 500, "succeeded": false }
+                    ]
+                }]
+            }
+        }"#
+    );
+
+    let delete_webhook = format!(r#"mutation {{
+        deleteWebhook(id: "{}") {{
+            id
+        }}
+    }}"#, webhook_id);
+    test_query_error_as(&app, Some("globex"), &delete_webhook, "NOT_FOUND");
+    run_query_as(&app, acme, &delete_webhook);
+    test_query_as(&app, acme,
+        r#"{
+            webhooks(tenantId: "acme") {
+                id
+            }
+        }"#,
+        r#"{ "data": { "webhooks": [] } }"#
+    );
+}
+
+#[test]
+fn basket_tenant_test() {
+    // Verify that implicitly created baskets can be assigned to a tenant
+    let db = Arc::new(MemoryDatabase::new());
+    let app = create_app(db.clone(), Dispatcher::new());
+    let basket_id = "1d3f5b7c-9e0a-4c2e-8f4a-6b8d0f2a4c6e";
+    let set_tenant = |basket_id: &str, tenant_id: &str| format!(r#"mutation {{
+        setBasketTenant(basketId: "{}", tenantId: "{}") {{
+            tenantId
+        }}
+    }}"#, basket_id, tenant_id);
+
+    // Assigning a tenant never creates the basket
+    test_query_error_as(&app, Some("acme"), &set_tenant(basket_id, "acme"), "NOT_FOUND");
+    test_query(&app, &format!(r#"{{ basket(id: "{}") {{ id }} }}"#, basket_id), r#"{ "data": { "basket": null } }"#);
+
+    let add_profile = |profile_id: &str| format!(r#"mutation {{
+        addProfile(basketId: "{}", profileId: "{}") {{
+            id
+        }}
+    }}"#, basket_id, profile_id);
+    run_query(&app, &add_profile("2e4a6c8e-0f1b-4d3f-9a5c-7e9b1d3f5a7c"));
+
+    test_query_error(&app, &set_tenant(basket_id, "acme"), "FORBIDDEN");
+    test_query_error_as(&app, Some("globex"), &set_tenant(basket_id, "acme"), "FORBIDDEN");
+    let (_, response) = run_query_as(&app, Some("acme"), &set_tenant(basket_id, " acme "));
+    assert_eq!(response["data"]["setBasketTenant"]["tenantId"], "acme");
+    run_query_as(&app, Some("acme"), &set_tenant(basket_id, "acme"));
+    test_query_error_as(&app, Some("globex"), &set_tenant(basket_id, "globex"), "FORBIDDEN");
+
+    // `basket.created` is only sent for baskets created with a tenant
+    assert!(db.outbox_messages().is_empty());
+
+    // Once it belongs to a tenant, only that tenant can use the basket
+    let query = format!(r#"{{ basket(id: "{}") {{ tenantId }} }}"#, basket_id);
+    test_query_error(&app, &query, "FORBIDDEN");
+    test_query_error_as(&app, Some("globex"), &query, "FORBIDDEN");
+    test_query_as(&app, Some("acme"), &query, r#"{ "data": { "basket": { "tenantId": "acme" } } }"#);
+    test_query_error(&app, &add_profile("3f5b7d9f-1a2c-4e4a-8b6d-8f0c2e4a6b8d"), "FORBIDDEN");
+    run_query_as(&app, Some("acme"), &add_profile("3f5b7d9f-1a2c-4e4a-8b6d-8f0c2e4a6b8d"));
+    let messages = db.outbox_messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].topic, WEBHOOK_EVENT);
+    assert_eq!(messages[0].payload["event"], "profile.added");
+    assert_eq!(messages[0].payload["tenant_id"], "acme");
+    assert_eq!(messages[0].payload["basket_id"], basket_id);
 }